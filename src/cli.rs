@@ -1,21 +1,111 @@
+use crate::conflicts::ConflictStrategy;
+use crate::encoding::NormalizationForm;
+use crate::filesystem::SymlinkTarget;
+use crate::sorting::SortKey;
 use clap::Parser;
+use std::ffi::OsString;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, about, long_about = None, disable_help_flag = true)]
-
 /// Template for cli args. See `clap` documentation for move information
 pub struct CliArgs {
     /// Show help message
     #[arg(short = 'h', long = "help")]
     pub help: bool,
 
-    /// Pattern to match files
-    pub pattern: Option<String>,
+    /// Pattern to match files. May contain bytes, which are not valid UTF-8
+    pub pattern: Option<OsString>,
 
-    /// New name format
-    pub rename_template: Option<String>,
+    /// New name format. Literal text may contain bytes, which are not valid UTF-8
+    pub rename_template: Option<OsString>,
 
-    /// Force overwriting of existing files
+    /// Force overwriting of existing files. The same as `--on-conflict overwrite`
     #[arg(short = 'f', long = "force")]
     pub force_overwrite: bool,
+
+    /// What to do when a new name is taken by an existing file or by another matched file: error (default), skip, overwrite, rename (`name_1.ext`), rename-paren (`name (1).ext`), newer, larger or ask
+    #[arg(long = "on-conflict", value_name = "STRATEGY", conflicts_with_all = ["force_overwrite", "append"])]
+    pub on_conflict: Option<ConflictStrategy>,
+
+    /// Order of matched files: name, natural, mtime or size. Files are moved and numbered in this order
+    #[arg(short = 's', long = "sort", default_value = "name")]
+    pub sort: SortKey,
+
+    /// Make new names portable: replace characters reserved on Windows, strip trailing dots and spaces, avoid device names like `CON` and limit name length
+    #[arg(long = "sanitize")]
+    pub sanitize: bool,
+
+    /// Print planned moves without moving anything
+    #[arg(short = 'n', long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Convert names of matched files and their directories from the given encoding (e.g. cp1251 or koi8-r) to UTF-8
+    #[arg(long = "from-encoding", value_name = "ENCODING")]
+    pub from_encoding: Option<String>,
+
+    /// Convert names of matched files and their directories from UTF-8 to the given encoding
+    #[arg(long = "to-encoding", value_name = "ENCODING")]
+    pub to_encoding: Option<String>,
+
+    /// Convert names of matched files and their directories to the given Unicode normalization form: nfc or nfd
+    #[arg(long = "normalize", value_name = "FORM")]
+    pub normalize: Option<NormalizationForm>,
+
+    /// Copy files instead of moving them, keeping permissions, timestamps and extended attributes. The same as running `mmv` as `mcp`
+    #[arg(short = 'c', long = "copy", conflicts_with_all = ["link", "symlink", "append"])]
+    pub copy: bool,
+
+    /// Create hard links with new names instead of moving files
+    #[arg(short = 'l', long = "link", conflicts_with_all = ["symlink", "append"])]
+    pub link: bool,
+
+    /// Create symbolic links with new names instead of moving files. Links point to files with pathes relative to the link (default) or absolute ones: `--symlink=absolute`
+    #[arg(long = "symlink", value_name = "TARGET", num_args = 0..=1, require_equals = true, default_missing_value = "relative")]
+    pub symlink: Option<SymlinkTarget>,
+
+    /// Append contents of files to files with new names, which are created if needed. Files mapped to the same name are concatenated in `--sort` order. The same as running `mmv` as `mad`
+    #[arg(short = 'a', long = "append", conflicts_with = "symlink")]
+    pub append: bool,
+
+    /// Create missing parent directories of new names
+    #[arg(short = 'p', long = "create-dirs")]
+    pub create_dirs: bool,
+
+    /// Permissions of directories created with `--create-dirs`, in octal, e.g. 750. By default they are 777 limited by umask
+    #[arg(long = "dir-mode", value_name = "MODE", value_parser = parse_mode, requires = "create_dirs")]
+    pub dir_mode: Option<u32>,
+
+    /// Remove directories, which become empty because files were moved out of them. Directories, which were empty before, and the current directory are kept
+    #[arg(long = "prune-empty")]
+    pub prune_empty: bool,
+
+    /// Match directories too. A matched directory is moved with all its contents, which are not matched on their own then
+    #[arg(short = 'd', long = "dirs")]
+    pub dirs: bool,
+
+    /// Move all matched files directly into the given directory. New names are pathes of files with `/` replaced by `--separator`, taken names get a numeric suffix. No rename template is needed
+    #[arg(long = "flatten", value_name = "DIR", conflicts_with_all = ["from_encoding", "to_encoding", "normalize"])]
+    pub flatten: Option<PathBuf>,
+
+    /// Separator of path components in names produced by `--flatten`
+    #[arg(
+        long = "separator",
+        value_name = "SEP",
+        default_value = "_",
+        requires = "flatten"
+    )]
+    pub separator: String,
+
+    /// Reverse the order of matched files
+    #[arg(short = 'r', long = "reverse")]
+    pub reverse: bool,
+}
+
+/// Parses octal permission bits like `755`
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("`{}` is not an octal mode like 755", mode))
 }
@@ -0,0 +1,331 @@
+use colored::Colorize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What to do when a new name is taken, either by an existing file or by another file of the plan
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictStrategy {
+    /// Report every conflict and move nothing, the default. Names taken by existing files are checked by `MassMoveController::check_existing_targets`, clashes inside the plan by `MassMoveController::check_collisions`
+    Error,
+    /// Leave the file, which would take a taken name, where it is
+    Skip,
+    /// Replace the existing file, like `-f` does. Of several files mapped to the same name the last one wins
+    Overwrite,
+    /// Give the file a free name with a numeric suffix
+    Rename(Numbering),
+    /// Keep whichever of the conflicting files was modified last
+    KeepNewer,
+    /// Keep whichever of the conflicting files is the largest
+    KeepLarger,
+    /// Ask what to do with every conflict
+    Ask,
+}
+
+/// Style of numeric suffixes, which make a taken name free
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Numbering {
+    /// `name_1.ext`
+    Underscore,
+    /// `name (1).ext`, like file managers do
+    Parenthesized,
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = String;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "error" => Ok(ConflictStrategy::Error),
+            "skip" => Ok(ConflictStrategy::Skip),
+            "overwrite" => Ok(ConflictStrategy::Overwrite),
+            "rename" => Ok(ConflictStrategy::Rename(Numbering::Underscore)),
+            "rename-paren" => Ok(ConflictStrategy::Rename(Numbering::Parenthesized)),
+            "newer" => Ok(ConflictStrategy::KeepNewer),
+            "larger" => Ok(ConflictStrategy::KeepLarger),
+            "ask" => Ok(ConflictStrategy::Ask),
+            _ => Err(format!(
+                "Unknown conflict strategy `{}`, expected one of: error, skip, overwrite, rename, rename-paren, newer, larger, ask",
+                strategy
+            )),
+        }
+    }
+}
+
+impl ConflictStrategy {
+    /// Whether existing files may be replaced once conflicts are resolved
+    pub fn overwrites(&self) -> bool {
+        matches!(
+            self,
+            ConflictStrategy::Overwrite
+                | ConflictStrategy::KeepNewer
+                | ConflictStrategy::KeepLarger
+                | ConflictStrategy::Ask
+        )
+    }
+}
+
+/// Outcome of a single conflict
+#[derive(Clone, Copy)]
+enum Decision {
+    /// The file, which already has the name, keeps it. The conflicting file is left where it is
+    Keep,
+    /// The conflicting file takes the name. An earlier planned file is left where it is, an existing one is replaced
+    Replace,
+    /// The conflicting file gets a free name with a numeric suffix
+    Rename(Numbering),
+}
+
+/// `path` with number `number` appended to its stem, e.g. `photos/a_2.jpg` or `photos/a (2).jpg` for `photos/a.jpg` and 2. Used to give distinct names to files, which would take the same one
+pub fn numbered_name(path: &Path, number: usize, numbering: Numbering) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().as_bytes();
+    let (stem, extension) = match name.iter().rposition(|byte| *byte == b'.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, &b""[..]),
+    };
+    let mut numbered = stem.to_vec();
+    let suffix = match numbering {
+        Numbering::Underscore => format!("_{}", number),
+        Numbering::Parenthesized => format!(" ({})", number),
+    };
+    numbered.extend_from_slice(suffix.as_bytes());
+    numbered.extend_from_slice(extension);
+    path.with_file_name(OsStr::from_bytes(&numbered))
+}
+
+/// Applies `strategy` to every planned move, which new name is taken by an existing file or by an earlier move of the plan. Every resolved conflict is reported.
+///
+/// # Arguments
+///
+/// * `plan` -- planned moves
+/// * `sources_leave` -- whether sources of the plan are moved away. A source, which is moved away, does not take its name, as moves are ordered so that it leaves before another file comes, see `MassMoveController::order_moves`
+/// * `input` -- answers to questions of `ConflictStrategy::Ask`, one per line
+///
+/// # Returns
+///
+/// Plan without skipped moves and with renamed targets. `ConflictStrategy::Error` returns `plan` as it is, conflicts are reported before anything is moved, see `ConflictStrategy::Error`
+pub fn resolve_conflicts(
+    plan: Vec<(PathBuf, PathBuf)>,
+    strategy: ConflictStrategy,
+    sources_leave: bool,
+    input: &mut impl BufRead,
+) -> Vec<(PathBuf, PathBuf)> {
+    if strategy == ConflictStrategy::Error {
+        return plan;
+    }
+    let mut leaving: HashSet<PathBuf> = if sources_leave {
+        plan.iter()
+            .filter(|(file, new_name)| file != new_name)
+            .map(|(file, _)| file.clone())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    let mut answers = HashMap::new();
+    // A skipped move leaves its source in place, which may take a name given away before. So conflicts are resolved again until every source assumed to leave does
+    loop {
+        let (resolved, reports) = resolve_once(&plan, strategy, &leaving, &mut answers, input);
+        let kept: HashSet<&PathBuf> = resolved.iter().map(|(file, _)| file).collect();
+        let staying: Vec<PathBuf> = leaving
+            .iter()
+            .filter(|file| !kept.contains(file))
+            .cloned()
+            .collect();
+        if staying.is_empty() {
+            for report in reports {
+                println!("{}", report);
+            }
+            return resolved;
+        }
+        for file in staying {
+            leaving.remove(&file);
+        }
+    }
+}
+
+/// Single pass of `resolve_conflicts`, assuming exactly the files in `leaving` are moved away. Decisions of `ConflictStrategy::Ask` are remembered in `answers`, so a file is asked about once
+fn resolve_once(
+    plan: &[(PathBuf, PathBuf)],
+    strategy: ConflictStrategy,
+    leaving: &HashSet<PathBuf>,
+    answers: &mut HashMap<PathBuf, Decision>,
+    input: &mut impl BufRead,
+) -> (Vec<(PathBuf, PathBuf)>, Vec<String>) {
+    let exists = |path: &Path| !leaving.contains(path) && path.symlink_metadata().is_ok();
+    let mut reports = vec![];
+    let mut resolved: Vec<Option<(PathBuf, PathBuf)>> = vec![];
+    let mut claimed: HashMap<PathBuf, usize> = HashMap::new();
+    for (file, new_name) in plan {
+        let earlier = claimed.get(new_name).copied();
+        if file == new_name || (earlier.is_none() && !exists(new_name)) {
+            claimed.insert(new_name.clone(), resolved.len());
+            resolved.push(Some((file.clone(), new_name.clone())));
+            continue;
+        }
+        let occupant = match earlier {
+            Some(index) => resolved[index].as_ref().unwrap().0.clone(),
+            None => new_name.clone(),
+        };
+        let taken_by = match earlier {
+            Some(_) => format!("is taken by {}", occupant.display()),
+            None => "exists".to_string(),
+        };
+        let decision = match strategy {
+            ConflictStrategy::Error => unreachable!(),
+            ConflictStrategy::Skip => Decision::Keep,
+            ConflictStrategy::Overwrite => Decision::Replace,
+            ConflictStrategy::Rename(numbering) => Decision::Rename(numbering),
+            ConflictStrategy::KeepNewer => prefer(file, &occupant, |metadata| metadata.modified()),
+            ConflictStrategy::KeepLarger => prefer(file, &occupant, |metadata| Ok(metadata.len())),
+            ConflictStrategy::Ask => *answers
+                .entry(file.clone())
+                .or_insert_with(|| ask(file, new_name, &taken_by, input)),
+        };
+        match decision {
+            Decision::Keep => reports.push(format!(
+                "Skipping file: {} ({} {})",
+                file.display().to_string().yellow(),
+                new_name.display(),
+                taken_by
+            )),
+            Decision::Replace => {
+                if let Some(index) = earlier {
+                    reports.push(format!(
+                        "Skipping file: {} ({} is taken by {})",
+                        occupant.display().to_string().yellow(),
+                        new_name.display(),
+                        file.display()
+                    ));
+                    resolved[index] = None;
+                }
+                claimed.insert(new_name.clone(), resolved.len());
+                resolved.push(Some((file.clone(), new_name.clone())));
+            }
+            Decision::Rename(numbering) => {
+                // Names of sources are not used even if they leave, so renaming never makes moves depend on each other
+                let free_name = (1..)
+                    .map(|number| numbered_name(new_name, number, numbering))
+                    .find(|name| !claimed.contains_key(name) && name.symlink_metadata().is_err())
+                    .unwrap();
+                reports.push(format!(
+                    "Name {} is taken, using {}",
+                    new_name.display().to_string().yellow(),
+                    free_name.display().to_string().green()
+                ));
+                claimed.insert(free_name.clone(), resolved.len());
+                resolved.push(Some((file.clone(), free_name)));
+            }
+        }
+    }
+    let resolved: Vec<(PathBuf, PathBuf)> = resolved.into_iter().flatten().collect();
+    (resolved, reports)
+}
+
+/// Replaces `occupant` with `file` only if `key` of `file` is greater. Files, which key can not be read, lose
+fn prefer<T: Ord>(
+    file: &Path,
+    occupant: &Path,
+    key: impl Fn(&fs::Metadata) -> io::Result<T>,
+) -> Decision {
+    let key_of = |path: &Path| fs::metadata(path).and_then(|metadata| key(&metadata)).ok();
+    if key_of(file) > key_of(occupant) {
+        Decision::Replace
+    } else {
+        Decision::Keep
+    }
+}
+
+/// Asks what to do with `file`, which new name `new_name` exists or is taken by an earlier file of the plan, as `taken_by` tells. No answer means skip
+fn ask(file: &Path, new_name: &Path, taken_by: &str, input: &mut impl BufRead) -> Decision {
+    loop {
+        eprint!(
+            "{} -> {}: {} {}. [s]kip, [o]verwrite or [r]ename? ",
+            file.display().to_string().yellow(),
+            new_name.display().to_string().green(),
+            new_name.display(),
+            taken_by
+        );
+        let _ = io::stderr().flush();
+        let mut answer = String::new();
+        if input.read_line(&mut answer).unwrap_or(0) == 0 {
+            eprintln!();
+            return Decision::Keep;
+        }
+        match answer.trim() {
+            "s" | "skip" | "" => return Decision::Keep,
+            "o" | "overwrite" => return Decision::Replace,
+            "r" | "rename" => return Decision::Rename(Numbering::Underscore),
+            _ => continue,
+        }
+    }
+}
+
+#[test]
+fn test_numbered_name() {
+    assert_eq!(
+        numbered_name(Path::new("photos/a.tar.gz"), 2, Numbering::Underscore),
+        Path::new("photos/a.tar_2.gz")
+    );
+    assert_eq!(
+        numbered_name(Path::new(".bashrc"), 1, Numbering::Underscore),
+        Path::new(".bashrc_1")
+    );
+    assert_eq!(
+        numbered_name(Path::new("README"), 10, Numbering::Parenthesized),
+        Path::new("README (10)")
+    );
+    assert_eq!(
+        numbered_name(Path::new("a/b.jpg"), 1, Numbering::Parenthesized),
+        Path::new("a/b (1).jpg")
+    );
+}
+
+#[test]
+fn test_resolve_plan_collisions() {
+    let plan = |names: &[(&str, &str)]| -> Vec<(PathBuf, PathBuf)> {
+        names
+            .iter()
+            .map(|(file, new_name)| (PathBuf::from(file), PathBuf::from(new_name)))
+            .collect()
+    };
+    let colliding = plan(&[
+        ("mmv-missing/a", "mmv-missing/x"),
+        ("mmv-missing/b", "mmv-missing/x"),
+        ("mmv-missing/c", "mmv-missing/x"),
+    ]);
+    let resolve = |strategy, answers: &str| {
+        resolve_conflicts(colliding.clone(), strategy, true, &mut answers.as_bytes())
+    };
+    assert_eq!(resolve(ConflictStrategy::Error, ""), colliding);
+    assert_eq!(
+        resolve(ConflictStrategy::Skip, ""),
+        plan(&[("mmv-missing/a", "mmv-missing/x")])
+    );
+    assert_eq!(
+        resolve(ConflictStrategy::Overwrite, ""),
+        plan(&[("mmv-missing/c", "mmv-missing/x")])
+    );
+    assert_eq!(
+        resolve(ConflictStrategy::Rename(Numbering::Parenthesized), ""),
+        plan(&[
+            ("mmv-missing/a", "mmv-missing/x"),
+            ("mmv-missing/b", "mmv-missing/x (1)"),
+            ("mmv-missing/c", "mmv-missing/x (2)"),
+        ])
+    );
+    assert_eq!(
+        resolve(ConflictStrategy::Ask, "r\nwhat\no\n"),
+        plan(&[
+            ("mmv-missing/b", "mmv-missing/x_1"),
+            ("mmv-missing/c", "mmv-missing/x"),
+        ])
+    );
+    assert_eq!(
+        resolve(ConflictStrategy::Ask, ""),
+        plan(&[("mmv-missing/a", "mmv-missing/x")])
+    );
+}
@@ -1,12 +1,26 @@
-use std::{env, process::exit};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::{env, io, process::exit};
 
 use crate::cli::CliArgs;
-use crate::filesystem::FileSystemManager;
+use crate::conflicts::{numbered_name, resolve_conflicts, ConflictStrategy, Numbering};
+use crate::encoding::{find_encoding, Conversion};
+use crate::filesystem::{Action, FileSystemManager};
+use crate::hashing::{digest_files, FileDigests};
 use crate::matcher::Matcher;
+use crate::sanitize::sanitize_path;
+use crate::sorting::SortKey;
+use crate::template::{FileContext, TemplateError};
 use clap::{CommandFactory, Parser};
 use clap_help::Printer;
 use colored::Colorize;
 
+/// List of planned moves: current location and the new one
+type Plan = Vec<(PathBuf, PathBuf)>;
+
 /// Controller class is a simple class for running the entire `mmv` utility with 1 line from main.rs.
 /// It is used for convinient error handling and integration testing
 pub struct MassMoveController {}
@@ -21,7 +35,7 @@ impl MassMoveController {
             Mass move files with 1 command using simple pattern-matching technique.
             * Pattern must be a string with some `*` (star) symbols. Each of this characters will be replaced with a substings to make pattern a name of to be moved
             * Template is a string with some `#` symbols followed by natural numbers. Is some file matched pattern, each of `#` things will be replaced with corresponding pattern substings to construct a new filename
-            * If you want `mmv` to overwrite existing files, you should provide a `-f` flag, otherwise `mmv` will do nothing.
+            * If you want `mmv` to overwrite existing files, you should provide a `-f` flag, otherwise `mmv` will do nothing. `--on-conflict` offers other ways to handle taken names
 
             Here are a few examples on how `mmv` can be used:
 
@@ -29,6 +43,31 @@ impl MassMoveController {
             * `mmv old new` will behave just as an usual `mv` command and rename a single file if such exists.
             * `mmv a*c* a#1c` will trunk suffixes of all files matching `a*c*` template (meaning such files should start with `a` letter and containg `c` further).
             * `mmv 1*3 a#1`. If current directory contains files like 123, 1113, 13, 143, they will be renamed to a2, a11, a, a4 respectively
+            * `mmv 'ep*.mkv' 'ep#{1 + 12|pad:3}.mkv'` evaluates integer arithmetic on captures (`+ - * / %` and parentheses) and pads the result with zeros. Inside `#{...}` use `#N` for captures; if there are none, the first number names the capture
+            * `mmv '*.jpg' 'photo_#{n:start=1,width=4,sort=mtime}.jpg'` numbers matched files. Counter options are `start`, `step`, `width`, `reset=dir` (restart numbering in every directory), `size` (number of consecutive files sharing a value) and `sort` (`name`, `natural`, `mtime` or `size`, by default files are numbered in `--sort` order)
+            * `mmv '*_*.txt' '#{1[0:4]}_#{2:-unknown}_#{2/ /_}.txt'` takes characters 0..4 of a capture (negative bounds count from the end), substitutes a default for an empty capture and replaces regex matches in a capture (`$1` refers to regex groups, `\\/` is a slash). Use `\\|` for `|` inside braces
+            * `mmv '*_*.txt' '#1#{?2:_v#2}.txt'` adds `_v#2` only if the second capture is not empty. Conditions are `#{?2:THEN:ELSE}`, `#{?!2:...}` (capture is empty) and `#{?2~REGEX:...}` (capture matches `REGEX`). Write `\\:` for a literal colon inside a conditional section
+            * `mmv 'uploads/*.jpg' 'uploads/#{1|slug}.jpg'` makes portable ASCII names: `|translit` transliterates Cyrillic, Greek and accented Latin letters, `|slug` also lowercases the text and collapses whitespace and punctuation into `-` (`|slug:_` uses another separator)
+            * `mmv 'report_*.pdf' 'report_#{1|date:%d.%m.%Y=>%Y-%m-%d}.pdf'` parses a capture as a date with the first `strftime` format and prints it with the second one, e.g. `18.10.2026` becomes `2026-10-18`. Values not matching the format are reported before anything is moved
+            * `mmv '*.pdf' '#{1|map:codes.csv}.pdf'` replaces a value with the second column of the row of a CSV (or TSV) file, which first column is this value. The table is read once. A value missing in the table is an error, `map:codes.csv:keep` keeps it and `map:codes.csv:-Other` substitutes a default
+            * `mmv '*.jpeg' '#{dir}/#{stem}.jpg'` uses parts of the matched path: `#0` (whole path), `#{dir}`, `#{name}`, `#{stem}`, `#{ext}`, `#{parent}` and `#{parent:N}` (name of the `N`-th directory above the file)
+            * `mmv 'backup.tar' 'backup_#{mtime:%Y-%m-%d_%H%M}.tar'` uses file metadata: `#{mtime:FORMAT}`, `#{ctime:FORMAT}` (`strftime` format, `%Y-%m-%d` by default), `#{size}`, `#{size:human}`, `#{owner}` and `#{mode}`
+            * `mmv 'assets/*.png' 'assets/#{sha256:12}.png'` renames files to their content digest: `#{sha256}`, `#{blake3}` or `#{md5}`, optionally truncated to the given number of hex characters. Files are hashed only if the template uses a hash
+            * `mmv '*' '#{shard:2}/#1'` spreads files into subdirectories named after the first 2 hex characters of their SHA-256 digest, `mmv '*' '#{bucket:1000}/#1'` puts every 1000 consecutive files into directories `0`, `1` and so on
+            * `mmv --sanitize '*' '#1'` makes new names portable to Windows, macOS and FAT filesystems: replaces `<>:\"|?*\\` with `_`, strips trailing dots and spaces, appends `_` to device names like `CON` and truncates names longer than 255 bytes. Every sanitized name is reported
+            * `mmv --from-encoding cp1251 '*'` converts names of matched files and of their directories from a legacy encoding to UTF-8, names which are already valid UTF-8 are left as they are. `--to-encoding koi8-r` does the opposite, `--normalize nfc` (or `nfd`) converts names to the given Unicode normalization form. No rename template is needed in these modes, and a name colliding with an existing file after conversion is reported before anything is moved
+            * Patterns are matched in Unicode NFC form, so names stored by macOS in NFD match patterns typed in NFC
+            * Names, which are not valid UTF-8, are matched and renamed as raw bytes: captures, `#0` and path parts keep the original bytes, text operations and filters see invalid bytes as `U+FFFD`
+            * `mmv --copy '*.conf.template' '#1.conf'` (or `mcp '*.conf.template' '#1.conf'`, with `mcp` being a link to `mmv`) copies files instead of moving them, keeping permissions, timestamps and extended attributes. Existing files are overwritten only with `-f`
+            * `mmv --link 'raw/*.csv' 'by-name/#1.csv'` creates hard links instead of moving files, `--symlink` creates symbolic links pointing to files with pathes relative to the link, `--symlink=absolute` uses absolute pathes. Existing files are replaced only with `-f`
+            * `mmv --append --sort natural 'log_*_part*.txt' 'log_#1.txt'` (or `mad`, with `mad` being a link to `mmv`) appends contents of matched files to files with new names, creating them if needed. Files mapped to the same name are concatenated in `--sort` order and kept as they are
+            * `mmv -p 'img_*_*.jpg' '#1/#2.jpg'` (`--create-dirs`) creates missing directories of new names, `--dir-mode 750` sets their permissions. Dry run lists directories, which would be created, and if the run fails, created directories are removed again as long as they are empty
+            * `mmv --prune-empty '*/*/*.jpg' '#1/#3.jpg'` removes directories, which become empty because files were moved out of them. Directories, which were empty before, and the current directory are kept
+            * `mmv --dirs 'photos_*' 'archive/#1'` (`-d`) matches directories too. A matched directory is moved with all its contents, which are not matched on their own
+            * `mmv --flatten all 'photos/*.jpg'` moves matched files directly into `all`, encoding their pathes into names: `photos/2025/trip/a.jpg` becomes `all/photos_2025_trip_a.jpg` (pathes inside the target directory are taken relative to it). `--separator -` joins path components with another separator. Names, which are already taken, get a numeric suffix like `a_1.jpg`, unless `--on-conflict` asks for something else. `--sanitize` applies to flattened names too. No rename template is needed
+            * `mmv --on-conflict rename '*/*.jpg' 'all/#2.jpg'` resolves names taken by existing files or by other matched files before anything is moved. Strategies are `error` (default), `skip`, `overwrite` (last matched file wins), `rename` (`name_1.ext`), `rename-paren` (`name (1).ext`), `newer` and `larger` (keep the file modified last or the largest one) and `ask`. Every resolved conflict is reported
+            * `mmv --dry-run 'a*' 'b#1'` only prints planned moves. Before anything is moved, `mmv` checks that no two files would be moved to the same location. A file may take the name of another moved file, moves are ordered so that it leaves first, but files moved in a cycle are reported
+            * `mmv --sort natural --reverse 'img*' 'img#1'` moves files in the given order: `name` (default), `natural` (`img9` before `img10`), `mtime` or `size`
 
             Note that `mmv` may operate files with in all subdirectories of current directory, but **does not** work with absolute pathes. Sometimes `mmv` may act correctly with absolute pathes, but use it on your own risk as it may break some important operating system files you user has such permission. Also `mmv` does not support windows-style pathes (e.g. C:\\ \\User), so please use only *relative unix-style pathes*.
 
@@ -44,43 +83,637 @@ impl MassMoveController {
             eprintln!("{}", "Pattern not provided. Please run mmv command with pattern as a first positional argument. See --help for documentation".red());
             exit(exitcode::DATAERR);
         }
-        if arguments.rename_template.is_none() {
+        let conversion = Self::conversion(&arguments);
+        if arguments.flatten.is_some() && arguments.rename_template.is_some() {
+            eprintln!("{}", "Rename template can not be used together with --flatten, as new names are the flattened pathes".red());
+            exit(exitcode::USAGE);
+        }
+        if arguments.separator.contains('/') {
+            eprintln!("{}", "Separator of --flatten can not contain `/`".red());
+            exit(exitcode::USAGE);
+        }
+        if arguments.rename_template.is_none()
+            && conversion.is_none()
+            && arguments.flatten.is_none()
+        {
             eprintln!("{}", "Rename template not provided. Please run mmv command with rename template as a second positional argument. See --help for documentation".red());
             exit(exitcode::DATAERR);
         }
-        let filesystem_manager =
-            FileSystemManager::new(env::current_dir().ok(), arguments.force_overwrite);
-        let filenames = filesystem_manager.get_filenames();
-        let pattern = filesystem_manager.normalize_path(&arguments.pattern.unwrap());
-        let rename_template =
-            filesystem_manager.normalize_path(&arguments.rename_template.unwrap());
-        let matcher = Matcher::new(pattern.clone());
-        let mut moved_any = false;
-        for file in &filenames {
-            let normalized_path = file;
-            if let Some(changes) = matcher.pattern_matcher(&normalized_path) {
-                let new_name = matcher.fill_in_template(
-                    changes.iter().map(|x| x.as_ref()).collect(),
-                    &rename_template,
+        let on_conflict = arguments.on_conflict.unwrap_or(ConflictStrategy::Error);
+        let filesystem_manager = FileSystemManager::new(
+            env::current_dir().ok(),
+            arguments.force_overwrite || on_conflict.overwrites(),
+        );
+        let action = if let Some(target) = arguments.symlink {
+            Action::Symlink(target)
+        } else if arguments.link {
+            Action::Link
+        } else if arguments.append || Self::invoked_as("mad") {
+            Action::Append
+        } else if arguments.copy || Self::invoked_as("mcp") {
+            Action::Copy
+        } else {
+            Action::Move
+        };
+        let pattern =
+            filesystem_manager.normalize_path(Path::new(arguments.pattern.as_ref().unwrap()));
+        let matcher = Matcher::new(pattern.as_os_str().as_bytes());
+        let mut plan = match (conversion, &arguments.flatten) {
+            (Some(conversion), _) => {
+                Self::plan_conversion(&filesystem_manager, &matcher, conversion, on_conflict)
+            }
+            (None, Some(directory)) => Self::plan_flatten(
+                &filesystem_manager,
+                &matcher,
+                &arguments,
+                directory,
+                on_conflict,
+            ),
+            (None, None) => Self::plan_renames(&filesystem_manager, &matcher, &arguments),
+        };
+        if plan.is_empty() {
+            eprintln!(
+                "No files for pattern {} can be moved!",
+                pattern.display().to_string().red()
+            );
+            exit(exitcode::DATAERR);
+        }
+        if action == Action::Append {
+            Self::check_append_sources(&plan);
+        } else {
+            plan = resolve_conflicts(
+                plan,
+                on_conflict,
+                action == Action::Move,
+                &mut io::stdin().lock(),
+            );
+            if on_conflict == ConflictStrategy::Error && !arguments.force_overwrite {
+                Self::check_existing_targets(&plan, action == Action::Move);
+            }
+            Self::check_collisions(&plan, action == Action::Move);
+            if action == Action::Move {
+                plan = Self::order_moves(plan);
+            }
+        }
+        let missing_directories = if arguments.create_dirs {
+            filesystem_manager
+                .missing_directories(plan.iter().map(|(_, new_name)| new_name.as_path()))
+        } else {
+            vec![]
+        };
+        let emptied_directories = if arguments.prune_empty && action == Action::Move {
+            filesystem_manager.directories_emptied_by(&plan)
+        } else {
+            vec![]
+        };
+        if arguments.dry_run {
+            for directory in &missing_directories {
+                println!(
+                    "Would create directory: {}",
+                    directory.display().to_string().green()
+                );
+            }
+            for (file, new_name) in &plan {
+                println!(
+                    "Would {} file: {} -> {}",
+                    action.verb(),
+                    file.display().to_string().red(),
+                    new_name.display().to_string().green()
+                );
+            }
+            for directory in &emptied_directories {
+                println!(
+                    "Would remove empty directory: {}",
+                    directory.display().to_string().red()
+                );
+            }
+            return;
+        }
+        let mut created_directories = vec![];
+        for directory in &missing_directories {
+            if let Err(err) = filesystem_manager.create_directory(directory, arguments.dir_mode) {
+                eprintln!(
+                    "{} {} {}\n{}",
+                    "Could not create directory".red(),
+                    directory.display().to_string().red(),
+                    "because of the following reason:".red(),
+                    err.to_string().red()
+                );
+                filesystem_manager.remove_created_directories(&created_directories);
+                return;
+            }
+            created_directories.push(directory.clone());
+        }
+        for (file, new_name) in &plan {
+            let file_move_result = filesystem_manager.apply(action, file, new_name);
+            if let Err(err) = file_move_result {
+                eprintln!(
+                    "{} {} {}\n{}",
+                    format!("Could not {} file", action.verb()).red(),
+                    file.display().to_string().red(),
+                    "because of the following reason:".red(),
+                    err.to_string().red()
+                );
+                filesystem_manager.remove_created_directories(&created_directories);
+                return;
+            }
+        }
+        filesystem_manager.remove_empty_directories(&emptied_directories);
+    }
+
+    /// Builds the list of moves for files matching `matcher`, constructing their new names from the rename template. Terminates `mmv` if the template can not be applied to some file, so nothing is moved in that case
+    fn plan_renames(
+        filesystem_manager: &FileSystemManager,
+        matcher: &Matcher,
+        arguments: &CliArgs,
+    ) -> Plan {
+        let rename_template = arguments.rename_template.as_ref().unwrap().as_bytes();
+        let template = matcher
+            .compile_template(rename_template)
+            .unwrap_or_else(|err| Self::template_failure(rename_template, err));
+        let matched = Self::match_files(filesystem_manager, matcher, arguments);
+        let matched_files: Vec<&Path> = matched.iter().map(|(file, _)| file.as_path()).collect();
+        let counters: Vec<Vec<i64>> = template
+            .counters()
+            .iter()
+            .map(|counter| {
+                counter
+                    .number(&matched_files)
+                    .unwrap_or_else(|err| Self::template_failure(rename_template, err))
+            })
+            .collect();
+        let hash_algorithms = template.hash_algorithms();
+        let mut hashes = if hash_algorithms.is_empty() {
+            vec![FileDigests::new(); matched_files.len()]
+        } else {
+            digest_files(&matched_files, &hash_algorithms).unwrap_or_else(|err| {
+                eprintln!("{}", err.red());
+                exit(exitcode::IOERR);
+            })
+        };
+        let mut plan: Plan = vec![];
+        for (index, (file, changes)) in matched.iter().enumerate() {
+            let mut context = FileContext::new(file, changes.iter().map(Vec::as_slice).collect());
+            context.counters = counters.iter().map(|values| values[index]).collect();
+            context.hashes = std::mem::take(&mut hashes[index]);
+            let new_name = matcher
+                .fill_in_template(&context, &template)
+                .unwrap_or_else(|err| Self::template_failure(rename_template, err));
+            let mut new_name =
+                filesystem_manager.normalize_path(Path::new(OsStr::from_bytes(&new_name)));
+            if arguments.sanitize {
+                new_name = Self::sanitize(new_name);
+            }
+            // Empty name, `.` or `..` normalizes to a directory, which can not be a new name of a file
+            if new_name.file_name().is_none() {
+                Self::template_failure(
+                    rename_template,
+                    TemplateError {
+                        message: format!("New name of {} is empty", file.display()),
+                        position: 0,
+                    },
                 );
-                let file_move_result = filesystem_manager.move_file(file, &new_name.to_string());
-                if let Err(err) = file_move_result {
-                    eprintln!(
-                        "{} {} {}\n{}",
-                        "Could not move file".red(),
-                        file.red(),
-                        "because of the following reason:".red(),
-                        err.to_string().red()
-                    );
-                    return;
-                } else {
-                    moved_any = true;
+            }
+            plan.push((file.clone(), new_name));
+        }
+        plan
+    }
+
+    /// Makes `new_name` portable for `--sanitize`, reporting the change. Names, which are not valid UTF-8, are not portable either, so invalid bytes become `U+FFFD`
+    fn sanitize(new_name: PathBuf) -> PathBuf {
+        let sanitized = PathBuf::from(sanitize_path(&new_name.to_string_lossy()));
+        if sanitized == new_name {
+            return new_name;
+        }
+        println!(
+            "Sanitizing name: {} -> {}",
+            new_name.display().to_string().yellow(),
+            sanitized.display().to_string().green()
+        );
+        sanitized
+    }
+
+    /// Finds files (and directories with `--dirs`) matching `matcher` in `--sort` order, together with their star substrings.
+    /// Contents of a matched directory are not matched on their own, as they are moved along with it
+    fn match_files(
+        filesystem_manager: &FileSystemManager,
+        matcher: &Matcher,
+        arguments: &CliArgs,
+    ) -> Vec<(PathBuf, Vec<Vec<u8>>)> {
+        let mut filenames = filesystem_manager.get_filenames();
+        if arguments.dirs {
+            filenames.extend(filesystem_manager.get_directories());
+        }
+        Self::sort_files(&mut filenames, arguments.sort, arguments.reverse);
+        let mut matched: Vec<(PathBuf, Vec<Vec<u8>>)> = filenames
+            .into_iter()
+            .filter_map(|file| {
+                matcher
+                    .pattern_matcher(file.as_os_str().as_bytes())
+                    .map(|changes| (file, changes))
+            })
+            .collect();
+        if arguments.dirs {
+            let matched_directories: HashSet<PathBuf> = matched
+                .iter()
+                .map(|(file, _)| file.clone())
+                .filter(|file| file.is_dir())
+                .collect();
+            matched.retain(|(file, _)| {
+                !file
+                    .ancestors()
+                    .skip(1)
+                    .any(|directory| matched_directories.contains(directory))
+            });
+        }
+        matched
+    }
+
+    /// Builds the list of moves, which put every file matching `matcher` directly into `directory`.
+    /// New name is the path of the file relative to `directory` (or to the current directory for files outside of it) with `/` replaced by `--separator`, e.g. `2025/trip/a.jpg` becomes `2025_trip_a.jpg`, made portable with `--sanitize`.
+    /// Flattening routinely produces duplicates, so unless `--on-conflict` asks for something else, names taken by other files or by existing ones get a numeric suffix: `a_1.jpg`, `a_2.jpg` and so on, or `a (1).jpg` with `rename-paren`
+    fn plan_flatten(
+        filesystem_manager: &FileSystemManager,
+        matcher: &Matcher,
+        arguments: &CliArgs,
+        directory: &Path,
+        on_conflict: ConflictStrategy,
+    ) -> Plan {
+        let numbering = match on_conflict {
+            ConflictStrategy::Error => Some(Numbering::Underscore),
+            ConflictStrategy::Rename(numbering) => Some(numbering),
+            // Other strategies are applied to the plan later, like to any other one
+            _ => None,
+        };
+        let directory = filesystem_manager.normalize_path(directory);
+        let mut taken: HashSet<PathBuf> = HashSet::new();
+        let mut plan: Plan = vec![];
+        for (file, _) in Self::match_files(filesystem_manager, matcher, arguments) {
+            let relative = match file.strip_prefix(&directory) {
+                Ok(relative) if !directory.as_os_str().is_empty() => relative,
+                _ => file.as_path(),
+            };
+            let name = relative
+                .components()
+                .map(|component| component.as_os_str().as_bytes())
+                .collect::<Vec<&[u8]>>()
+                .join(arguments.separator.as_bytes());
+            let mut new_name = directory.join(OsStr::from_bytes(&name));
+            if arguments.sanitize {
+                new_name = Self::sanitize(new_name);
+            }
+            let Some(numbering) = numbering else {
+                plan.push((file, new_name));
+                continue;
+            };
+            if new_name == file {
+                taken.insert(new_name);
+                continue;
+            }
+            let mut unique_name = new_name.clone();
+            let mut number = 0;
+            while taken.contains(&unique_name) || unique_name.symlink_metadata().is_ok() {
+                number += 1;
+                unique_name = numbered_name(&new_name, number, numbering);
+            }
+            if unique_name != new_name {
+                println!(
+                    "Name {} is taken, using {}",
+                    new_name.display().to_string().yellow(),
+                    unique_name.display().to_string().green()
+                );
+            }
+            taken.insert(unique_name.clone());
+            plan.push((file, unique_name));
+        }
+        plan
+    }
+
+    /// Builds the list of moves, which change encoding of names of files matching `matcher` and of directories on their pathes. Pattern is matched against the UTF-8 form of the path, which is the new path when converting to UTF-8 and the current one otherwise.
+    /// So only matched names are reported when they can not be converted. A name, which is not valid in the source encoding, has no UTF-8 form, it is reported if the lossy form of the path matches.
+    /// Every file and directory is renamed in place, deepest first, so pathes of the remaining moves stay valid: `д/ф.txt` is renamed inside `д` before `д` itself is
+    fn plan_conversion(
+        filesystem_manager: &FileSystemManager,
+        matcher: &Matcher,
+        conversion: Conversion,
+        on_conflict: ConflictStrategy,
+    ) -> Plan {
+        let files = filesystem_manager.get_filenames();
+        let directories = filesystem_manager.get_directories();
+        let existing: HashSet<&PathBuf> = files.iter().chain(&directories).collect();
+        let from_utf8 = !matches!(conversion, Conversion::FromEncoding(_));
+        let matches = |path: &Path| {
+            matcher
+                .pattern_matcher(path.as_os_str().as_bytes())
+                .is_some()
+        };
+        // Converted name of every file and directory seen so far, a directory is converted once for all files inside it
+        let mut converted: HashMap<PathBuf, Result<Option<Vec<u8>>, String>> = HashMap::new();
+        let mut renamed: HashSet<&Path> = HashSet::new();
+        let mut reported: HashSet<&Path> = HashSet::new();
+        let mut failed = false;
+        for file in &files {
+            // Names outside of the pattern are never converted, so they can not fail the run
+            if from_utf8 && !matches(file) {
+                continue;
+            }
+            let pathes: Vec<&Path> = file
+                .ancestors()
+                .take_while(|path| !path.as_os_str().is_empty())
+                .collect();
+            for path in &pathes {
+                converted.entry(path.to_path_buf()).or_insert_with(|| {
+                    conversion.convert_name(path.file_name().unwrap().as_bytes())
+                });
+            }
+            let failures: Vec<&Path> = pathes
+                .iter()
+                .copied()
+                .filter(|path| converted[*path].is_err())
+                .collect();
+            if !failures.is_empty() {
+                if from_utf8 || matches(Path::new(file.to_string_lossy().as_ref())) {
+                    for path in failures.into_iter().rev() {
+                        if reported.insert(path) {
+                            eprintln!("{}", converted[path].as_ref().unwrap_err().red());
+                        }
+                    }
+                    failed = true;
+                }
+                continue;
+            }
+            if !from_utf8 {
+                let new_path: PathBuf = pathes
+                    .iter()
+                    .rev()
+                    .map(|path| match &converted[*path] {
+                        Ok(Some(name)) => OsStr::from_bytes(name),
+                        _ => path.file_name().unwrap(),
+                    })
+                    .collect();
+                if !matches(&new_path) {
+                    continue;
                 }
             }
+            renamed.extend(
+                pathes
+                    .into_iter()
+                    .filter(|path| matches!(converted[*path], Ok(Some(_)))),
+            );
         }
-        if !moved_any {
-            eprintln!("No files for pattern {} can be moved!", pattern.red());
+        if failed {
             exit(exitcode::DATAERR);
         }
+        let mut plan: Plan = vec![];
+        for path in renamed {
+            let Ok(Some(name)) = &converted[path] else {
+                continue;
+            };
+            let new_name = path.with_file_name(OsStr::from_bytes(name));
+            // E.g. both NFC and NFD spelling of the same name may exist, converting one of them would silently overwrite the other
+            if on_conflict == ConflictStrategy::Error && existing.contains(&new_name) {
+                eprintln!(
+                    "{} {} {} {}",
+                    "File".red(),
+                    path.display().to_string().red(),
+                    "would collide with existing file".red(),
+                    new_name.display().to_string().red()
+                );
+                failed = true;
+                continue;
+            }
+            plan.push((path.to_path_buf(), new_name));
+        }
+        if failed {
+            exit(exitcode::DATAERR);
+        }
+        plan.sort_by(|(left, _), (right, _)| {
+            (Reverse(left.components().count()), left)
+                .cmp(&(Reverse(right.components().count()), right))
+        });
+        plan
+    }
+
+    /// Reads encoding conversion options. Terminates `mmv` if they are invalid
+    fn conversion(arguments: &CliArgs) -> Option<Conversion> {
+        let mut conversions = vec![];
+        if let Some(label) = &arguments.from_encoding {
+            conversions.push(find_encoding(label).map(Conversion::FromEncoding));
+        }
+        if let Some(label) = &arguments.to_encoding {
+            conversions.push(find_encoding(label).map(Conversion::ToEncoding));
+        }
+        if let Some(form) = arguments.normalize {
+            conversions.push(Ok(Conversion::Normalize(form)));
+        }
+        let conversion = match conversions.len() {
+            0 => return None,
+            1 => conversions.pop().unwrap(),
+            _ => Err(
+                "Only one of --from-encoding, --to-encoding and --normalize may be provided"
+                    .to_string(),
+            ),
+        };
+        let conversion = conversion.unwrap_or_else(|err| {
+            eprintln!("{}", err.red());
+            exit(exitcode::USAGE);
+        });
+        if arguments.rename_template.is_some() {
+            eprintln!("{}", "Rename template can not be used together with encoding conversion, as new names are the converted old ones".red());
+            exit(exitcode::USAGE);
+        }
+        Some(conversion)
+    }
+
+    /// Checks that no two files are moved to the same location and nothing is moved into a directory, which is moved itself, as the result would depend on the order of moves. Renaming a file in place before its directory is fine.
+    /// If files are `moved` away, also checks that they are not moved in a cycle, like `a -> b` and `b -> a`, as there is no order to make such moves in.
+    /// Terminates `mmv` before anything is moved otherwise
+    fn check_collisions(plan: &Plan, moved: bool) {
+        let mut sources_by_target: HashMap<&Path, &Path> = HashMap::new();
+        let sources: HashMap<&Path, usize> = plan
+            .iter()
+            .enumerate()
+            .map(|(index, (file, _))| (file.as_path(), index))
+            .collect();
+        let mut collided = false;
+        for (index, (file, new_name)) in plan.iter().enumerate() {
+            // A file renamed in place before its directory is renamed too, like encoding conversion does, stays in that directory whatever its new name is
+            let renamed_in_place = file.parent() == new_name.parent();
+            if let Some(directory) = new_name.ancestors().skip(1).find(|directory| {
+                sources
+                    .get(directory)
+                    .is_some_and(|moved| !renamed_in_place || *moved < index)
+            }) {
+                eprintln!(
+                    "{} {} {} {} {}",
+                    "File".red(),
+                    file.display().to_string().red(),
+                    "would be moved into directory".red(),
+                    directory.display().to_string().red(),
+                    "which is moved itself".red()
+                );
+                collided = true;
+            }
+            if let Some(other) = sources_by_target.insert(new_name, file) {
+                eprintln!(
+                    "{} {} {} {}",
+                    "Files".red(),
+                    format!("{} and {}", other.display(), file.display()).red(),
+                    "would both be moved to".red(),
+                    new_name.display().to_string().red()
+                );
+                collided = true;
+            }
+        }
+        if moved {
+            if let Err(cycle) = Self::move_order(plan) {
+                let files: Vec<String> = cycle
+                    .iter()
+                    .map(|index| plan[*index].0.display().to_string())
+                    .collect();
+                eprintln!(
+                    "{} {} {}",
+                    "Files".red(),
+                    files.join(", ").red(),
+                    "would be moved in a cycle, each to the name of another one".red()
+                );
+                collided = true;
+            }
+        }
+        if collided {
+            exit(exitcode::DATAERR);
+        }
+    }
+
+    /// Checks that no new name is taken by an existing file, which is not moved away by the plan itself, and reports all of them. Terminates `mmv` before anything is moved otherwise,
+    /// so a run, which can not be completed without `-f` or `--on-conflict`, is never left half-done
+    fn check_existing_targets(plan: &Plan, moved: bool) {
+        let leaving: HashSet<&Path> = if moved {
+            plan.iter()
+                .filter(|(file, new_name)| file != new_name)
+                .map(|(file, _)| file.as_path())
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let mut failed = false;
+        for (file, new_name) in plan {
+            if file != new_name
+                && !leaving.contains(new_name.as_path())
+                && new_name.symlink_metadata().is_ok()
+            {
+                eprintln!(
+                    "{} {} {} {}",
+                    "File".red(),
+                    file.display().to_string().red(),
+                    "would replace existing file".red(),
+                    new_name.display().to_string().red()
+                );
+                failed = true;
+            }
+        }
+        if failed {
+            eprintln!(
+                "{}",
+                "Nothing was moved. Use -f or --on-conflict to replace or keep existing files"
+                    .red()
+            );
+            exit(exitcode::DATAERR);
+        }
+    }
+
+    /// Orders moves so that every file is moved away before another one takes its name, e.g. `1 -> 0` goes before `2 -> 1`.
+    /// Moves, which do not depend on each other, keep their order. Cycles are rejected by `check_collisions` beforehand
+    fn order_moves(plan: Plan) -> Plan {
+        let order = Self::move_order(&plan).unwrap_or_else(|_| (0..plan.len()).collect());
+        let mut plan: Vec<Option<(PathBuf, PathBuf)>> = plan.into_iter().map(Some).collect();
+        order
+            .into_iter()
+            .map(|index| plan[index].take().unwrap())
+            .collect()
+    }
+
+    /// Indexes of `plan` in the order of `order_moves`, or indexes of moves forming a cycle
+    fn move_order(plan: &Plan) -> Result<Vec<usize>, Vec<usize>> {
+        let index_by_source: HashMap<&Path, usize> = plan
+            .iter()
+            .enumerate()
+            .filter(|(_, (file, new_name))| file != new_name)
+            .map(|(index, (file, _))| (file.as_path(), index))
+            .collect();
+        let mut ordered = vec![false; plan.len()];
+        let mut in_chain = vec![false; plan.len()];
+        let mut order = Vec::with_capacity(plan.len());
+        for start in 0..plan.len() {
+            // Every new name is the source of at most one move, so moves, which must go before `start`, form a chain
+            let mut chain = vec![];
+            let mut next = Some(start);
+            while let Some(index) = next.filter(|index| !ordered[*index]) {
+                if in_chain[index] {
+                    let position = chain.iter().position(|other| *other == index).unwrap();
+                    return Err(chain[position..].to_vec());
+                }
+                in_chain[index] = true;
+                chain.push(index);
+                next = index_by_source.get(plan[index].1.as_path()).copied();
+            }
+            for index in chain.iter().rev() {
+                ordered[*index] = true;
+                order.push(*index);
+            }
+        }
+        Ok(order)
+    }
+
+    /// Checks that no file is appended to a file, which is appended somewhere itself, as the result would depend on the order of appends. Terminates `mmv` before anything is appended otherwise.
+    /// Several files appended to the same target are fine, they are concatenated in the order of the plan
+    fn check_append_sources(plan: &Plan) {
+        let sources: HashSet<&Path> = plan.iter().map(|(file, _)| file.as_path()).collect();
+        let mut failed = false;
+        for (file, new_name) in plan {
+            if sources.contains(new_name.as_path()) {
+                eprintln!(
+                    "{} {} {} {}",
+                    "File".red(),
+                    file.display().to_string().red(),
+                    "would be appended to matched file".red(),
+                    new_name.display().to_string().red()
+                );
+                failed = true;
+            }
+        }
+        if failed {
+            exit(exitcode::DATAERR);
+        }
+    }
+
+    /// Orders `files` by `key`, so that moves, their output and counters are reproducible regardless of the filesystem walk order
+    fn sort_files(files: &mut Vec<PathBuf>, key: SortKey, reverse: bool) {
+        let names: Vec<&Path> = files.iter().map(|file| file.as_path()).collect();
+        let mut order = key.order(&names);
+        if reverse {
+            order.reverse();
+        }
+        *files = order
+            .into_iter()
+            .map(|index| files[index].clone())
+            .collect();
+    }
+
+    /// Checks if `mmv` was started through a link named `name`, e.g. `mcp`, like the classic `mmv` utility is
+    fn invoked_as(name: &str) -> bool {
+        env::args_os()
+            .next()
+            .is_some_and(|program| Path::new(&program).file_name() == Some(OsStr::new(name)))
+    }
+
+    /// Reports an error in rename template and terminates `mmv`. Called before any file is moved, so a bad template never leaves the job half-done
+    fn template_failure(template: &[u8], err: TemplateError) -> ! {
+        eprintln!(
+            "{}\n{}",
+            "Could not apply rename template:".red(),
+            err.pointing_at(template).red()
+        );
+        exit(exitcode::DATAERR);
     }
 }
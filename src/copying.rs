@@ -0,0 +1,220 @@
+use std::fs::{self, File, FileTimes, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Copies file located at `source` to `target`, keeping its permissions, owner (if allowed), access and modification times and extended attributes.
+/// Contents are cloned if the filesystem supports reflinks, otherwise they are copied in kernel with `copy_file_range` where available.
+/// Size of the copy is compared to the size of `source`
+///
+/// # Returns
+///
+/// Opened `target`, so the caller may `fsync` it
+pub fn copy_file_with_metadata(source: &Path, target: &Path) -> io::Result<File> {
+    let mut source_file = File::open(source)?;
+    let metadata = source_file.metadata()?;
+    // `target` never exists at this point, so a link left there by a race is not written through
+    let mut target_file = File::options().write(true).create_new(true).open(target)?;
+    if !reflink(&source_file, &target_file) {
+        io::copy(&mut source_file, &mut target_file)?;
+    }
+    if target_file.metadata()?.len() != metadata.len() {
+        return Err(io::Error::other(format!(
+            "mmv: Size of copied file {} differs from the size of {}",
+            target.display(),
+            source.display()
+        )));
+    }
+    xattrs::copy(&source_file, &target_file)?;
+    copy_metadata(&metadata, &target_file)?;
+    Ok(target_file)
+}
+
+/// Checks whether `left` and `right` are the same file, following symbolic links: both are links to one file, or one of them is a link to the other
+pub fn same_file(left: &Path, right: &Path) -> bool {
+    match (fs::metadata(left), fs::metadata(right)) {
+        (Ok(left), Ok(right)) => left.dev() == right.dev() && left.ino() == right.ino(),
+        _ => false,
+    }
+}
+
+/// Moves file or directory located at `source` to `target` on another filesystem, where `rename` fails with `EXDEV`.
+/// Everything is copied with `copy_file_with_metadata` to a hidden sibling of `target` first and flushed to disk, then the copy is renamed to `target` and only after that `source` is removed.
+/// If copying fails, the partial copy is removed and `source` is left untouched
+pub fn move_across_filesystems(source: &Path, target: &Path) -> io::Result<()> {
+    let staging = staging_name(target);
+    if let Err(err) = copy_tree(source, &staging) {
+        let _ = remove_tree(&staging);
+        return Err(err);
+    }
+    fs::rename(&staging, target).inspect_err(|_| {
+        let _ = remove_tree(&staging);
+    })?;
+    sync_parent(target)?;
+    remove_tree(source)
+}
+
+/// Hidden name in the directory of `target`, e.g. `dir/.name.mmv-partial` for `dir/name`
+fn staging_name(target: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(target.file_name().unwrap_or_default());
+    name.push(".mmv-partial");
+    target.with_file_name(name)
+}
+
+/// Copies `source` to `target` recursively: files with their metadata, symbolic links as links and directories with their contents and metadata. Everything is flushed to disk
+pub fn copy_tree(source: &Path, target: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    if metadata.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(source)?, target)
+    } else if metadata.is_dir() {
+        fs::create_dir(target)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &target.join(entry.file_name()))?;
+        }
+        let source_directory = File::open(source)?;
+        let target_directory = File::open(target)?;
+        xattrs::copy(&source_directory, &target_directory)?;
+        copy_metadata(&metadata, &target_directory)?;
+        target_directory.sync_all()
+    } else {
+        copy_file_with_metadata(source, target)?.sync_all()
+    }
+}
+
+/// Removes file, link or directory with all its contents
+fn remove_tree(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Flushes the directory entry of `path` to disk
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Applies owner, permissions and times from `metadata` to `target`. Changing owner is only possible for root, so failure to do it is ignored
+fn copy_metadata(metadata: &Metadata, target: &File) -> io::Result<()> {
+    let _ = std::os::unix::fs::fchown(target, Some(metadata.uid()), Some(metadata.gid()));
+    target.set_permissions(metadata.permissions())?;
+    target.set_times(
+        FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?),
+    )
+}
+
+/// Makes `target` share contents of `source` with copy-on-write, if the filesystem supports it (e.g. Btrfs or XFS)
+#[cfg(target_os = "linux")]
+fn reflink(source: &File, target: &File) -> bool {
+    use std::os::fd::AsRawFd;
+    unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &File, _target: &File) -> bool {
+    false
+}
+
+/// Extended attributes of open files. Attributes are copied only on Linux, on other systems `copy` does nothing
+mod xattrs {
+    use std::fs::File;
+    use std::io;
+
+    /// Copies all extended attributes of `source` to `target`. Does nothing if the filesystem of `source` does not support extended attributes.
+    /// Like `cp -a`, this is best effort: attributes, which the filesystem of `target` does not support or does not let to set, are skipped
+    #[cfg(target_os = "linux")]
+    pub fn copy(source: &File, target: &File) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        let (source, target) = (source.as_raw_fd(), target.as_raw_fd());
+        let names = read(|buffer, size| unsafe { libc::flistxattr(source, buffer, size) });
+        let names = match names {
+            Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+            names => names?,
+        };
+        for name in names
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+        {
+            let name = std::ffi::CString::new(name)?;
+            let value = read(|buffer, size| unsafe {
+                libc::fgetxattr(source, name.as_ptr(), buffer.cast(), size)
+            })?;
+            let result = unsafe {
+                libc::fsetxattr(target, name.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+            };
+            if result != 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::ENOTSUP) | Some(libc::EPERM) => continue,
+                    _ => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn copy(_source: &File, _target: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Calls `read_into` first to find out the size of the value, then to read it
+    #[cfg(target_os = "linux")]
+    fn read(read_into: impl Fn(*mut libc::c_char, usize) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+        let size = read_into(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; size as usize];
+        if buffer.is_empty() {
+            return Ok(buffer);
+        }
+        let size = read_into(buffer.as_mut_ptr().cast(), buffer.len());
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buffer.truncate(size as usize);
+        Ok(buffer)
+    }
+}
+
+#[test]
+fn test_move_across_filesystems() {
+    let root = std::env::temp_dir().join(format!("mmv-copying-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("source/nested")).unwrap();
+    fs::write(root.join("source/nested/a.txt"), "contents").unwrap();
+    std::os::unix::fs::symlink("nested/a.txt", root.join("source/link")).unwrap();
+    let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    File::options()
+        .write(true)
+        .open(root.join("source/nested/a.txt"))
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    move_across_filesystems(&root.join("source"), &root.join("target")).unwrap();
+    assert!(!root.join("source").exists());
+    assert!(!root.join(".target.mmv-partial").exists());
+    assert_eq!(
+        fs::read_to_string(root.join("target/link")).unwrap(),
+        "contents"
+    );
+    assert_eq!(
+        fs::metadata(root.join("target/nested/a.txt"))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        modified
+    );
+    assert!(move_across_filesystems(&root.join("missing"), &root.join("other")).is_err());
+    assert!(root.join("target").exists());
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,79 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{NaiveDate, NaiveDateTime};
+use std::fmt::{self, Write};
+
+/// Pair of `strftime`-like formats: text is parsed with `input` and printed with `output`, e.g. `%d.%m.%Y=>%Y-%m-%d` turns `18.10.2026` into `2026-10-18`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DateReformat {
+    input: String,
+    output: String,
+}
+
+impl DateReformat {
+    /// Parses `INPUT=>OUTPUT` formats. Fails if separator is missing, any of the formats is invalid or the output format needs a time zone (`%z`, `%Z`), which a parsed date does not have
+    pub fn parse(formats: &str) -> Result<Self, String> {
+        let Some((input, output)) = formats.split_once("=>") else {
+            return Err(format!(
+                "`date` expects input and output formats, e.g. `date:%d.%m.%Y=>%Y-%m-%d`, not `{}`",
+                formats
+            ));
+        };
+        for format in [input, output] {
+            if format.is_empty()
+                || StrftimeItems::new(format).any(|item| matches!(item, Item::Error))
+            {
+                return Err(format!("Invalid date format `{}`", format));
+            }
+        }
+        if format_date(&NaiveDateTime::default(), output).is_err() {
+            return Err(format!(
+                "Date format `{}` needs a time zone, which dates in names do not have",
+                output
+            ));
+        }
+        Ok(DateReformat {
+            input: input.to_string(),
+            output: output.to_string(),
+        })
+    }
+
+    /// Parses `text` with the input format and prints it with the output one. Input format may contain only a date, then time is midnight
+    pub fn apply(&self, text: &str) -> Result<String, String> {
+        let parsed = NaiveDateTime::parse_from_str(text, &self.input).or_else(|_| {
+            NaiveDate::parse_from_str(text, &self.input)
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap())
+        });
+        let parsed = parsed.map_err(|err| {
+            format!(
+                "`{}` does not match date format `{}`: {}",
+                text, self.input, err
+            )
+        })?;
+        format_date(&parsed, &self.output)
+            .map_err(|_| format!("Can not print `{}` with format `{}`", text, self.output))
+    }
+}
+
+/// Prints `date` with `format`. Unlike `to_string`, reports fields `date` lacks instead of panicking
+fn format_date(date: &NaiveDateTime, format: &str) -> Result<String, fmt::Error> {
+    let mut result = String::new();
+    write!(result, "{}", date.format(format))?;
+    Ok(result)
+}
+
+#[test]
+fn test_date_reformat() {
+    let reformat = DateReformat::parse("%d.%m.%Y=>%Y-%m-%d").unwrap();
+    assert_eq!(reformat.apply("18.10.2026").unwrap(), "2026-10-18");
+    assert!(reformat.apply("31.02.2026").is_err());
+    assert!(reformat.apply("2026-10-18").is_err());
+    let reformat = DateReformat::parse("%b %d %Y=>%Y%m%d").unwrap();
+    assert_eq!(reformat.apply("Oct 18 2026").unwrap(), "20261018");
+    let reformat = DateReformat::parse("%Y%m%d_%H%M=>%Y-%m-%d %H.%M").unwrap();
+    assert_eq!(reformat.apply("20261018_0930").unwrap(), "2026-10-18 09.30");
+    assert!(DateReformat::parse("%Y-%m-%d").is_err());
+    assert!(DateReformat::parse("%Y=>%Q").is_err());
+    assert!(DateReformat::parse("=>%Y").is_err());
+    assert!(DateReformat::parse("%d.%m.%Y=>%Y%z").is_err());
+    assert!(DateReformat::parse("%d.%m.%Y=>%Y %Z").is_err());
+}
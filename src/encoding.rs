@@ -0,0 +1,136 @@
+use encoding_rs::Encoding;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+/// Filename conversion, like `convmv` does: change of encoding or of Unicode normalization form
+#[derive(Debug, Clone, Copy)]
+pub enum Conversion {
+    /// Names are stored in the given legacy encoding and should become UTF-8
+    FromEncoding(&'static Encoding),
+    /// Names are UTF-8 and should be stored in the given legacy encoding
+    ToEncoding(&'static Encoding),
+    /// Names are UTF-8 and should be stored in the given Unicode normalization form
+    Normalize(NormalizationForm),
+}
+
+/// Unicode normalization form. Linux and Windows usually store names in NFC, while macOS produces NFD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalizationForm {
+    /// Composed form: `é` is a single character
+    Nfc,
+    /// Decomposed form: `é` is `e` followed by a combining accent
+    Nfd,
+}
+
+impl FromStr for NormalizationForm {
+    type Err = String;
+
+    fn from_str(form: &str) -> Result<Self, Self::Err> {
+        match form.to_ascii_lowercase().as_str() {
+            "nfc" => Ok(NormalizationForm::Nfc),
+            "nfd" => Ok(NormalizationForm::Nfd),
+            _ => Err(format!(
+                "Unknown normalization form `{}`, expected nfc or nfd",
+                form
+            )),
+        }
+    }
+}
+
+/// Looks up encoding by any of its common labels, e.g. `cp1251`, `windows-1251` or `koi8-r`
+pub fn find_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("Unknown encoding `{}`", label))
+}
+
+impl Conversion {
+    /// Converts raw bytes of a single filename
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(None)` -- name does not need conversion: it is already valid UTF-8 when converting to UTF-8, it is the same in both encodings or it is already normalized
+    /// * `Ok(Some(bytes))` -- converted name
+    /// * `Err` -- name can not be represented in the target encoding
+    pub fn convert_name(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            Conversion::FromEncoding(encoding) => {
+                if std::str::from_utf8(name).is_ok() {
+                    return Ok(None);
+                }
+                let (decoded, had_errors) = encoding.decode_without_bom_handling(name);
+                if had_errors {
+                    return Err(format!(
+                        "{} is not a valid {} name",
+                        String::from_utf8_lossy(name),
+                        encoding.name()
+                    ));
+                }
+                Ok(Some(decoded.into_owned().into_bytes()))
+            }
+            Conversion::ToEncoding(encoding) => {
+                let Ok(name) = std::str::from_utf8(name) else {
+                    return Ok(None);
+                };
+                let (encoded, _, had_errors) = encoding.encode(name);
+                if had_errors {
+                    return Err(format!(
+                        "{} can not be represented in {}",
+                        name,
+                        encoding.name()
+                    ));
+                }
+                if encoded.as_ref() == name.as_bytes() {
+                    return Ok(None);
+                }
+                Ok(Some(encoded.into_owned()))
+            }
+            Conversion::Normalize(form) => {
+                let Ok(name) = std::str::from_utf8(name) else {
+                    return Ok(None);
+                };
+                let normalized: String = match form {
+                    NormalizationForm::Nfc => name.nfc().collect(),
+                    NormalizationForm::Nfd => name.nfd().collect(),
+                };
+                if normalized == name {
+                    return Ok(None);
+                }
+                Ok(Some(normalized.into_bytes()))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_convert_name() {
+    let cp1251 = Conversion::FromEncoding(find_encoding("cp1251").unwrap());
+    assert_eq!(
+        cp1251.convert_name(&[0xcf, 0xf0, 0xe8, 0xe2, 0xe5, 0xf2]),
+        Ok(Some("Привет".as_bytes().to_vec()))
+    );
+    assert_eq!(cp1251.convert_name("Привет".as_bytes()), Ok(None));
+    let koi8 = Conversion::ToEncoding(find_encoding("koi8-r").unwrap());
+    assert_eq!(
+        koi8.convert_name("мир".as_bytes()),
+        Ok(Some(vec![0xcd, 0xc9, 0xd2]))
+    );
+    assert_eq!(koi8.convert_name(b"ascii.txt"), Ok(None));
+    assert!(koi8.convert_name("日本".as_bytes()).is_err());
+    assert!(find_encoding("no-such-encoding").is_err());
+}
+
+#[test]
+fn test_normalize_name() {
+    let nfd = Conversion::Normalize("NFD".parse().unwrap());
+    assert_eq!(
+        nfd.convert_name("caf\u{e9}".as_bytes()),
+        Ok(Some("cafe\u{301}".as_bytes().to_vec()))
+    );
+    let nfc = Conversion::Normalize(NormalizationForm::Nfc);
+    assert_eq!(
+        nfc.convert_name("cafe\u{301}".as_bytes()),
+        Ok(Some("caf\u{e9}".as_bytes().to_vec()))
+    );
+    assert_eq!(nfc.convert_name("caf\u{e9}".as_bytes()), Ok(None));
+    assert!("nfkc".parse::<NormalizationForm>().is_err());
+}
@@ -1,11 +1,71 @@
 extern crate exitcode;
 
+use crate::copying::{copy_file_with_metadata, copy_tree, move_across_filesystems, same_file};
 use colored::Colorize;
 use path_clean::clean;
+use std::collections::HashSet;
 use std::env;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
+use std::path::{Component, Path};
+use std::str::FromStr;
 use walkdir::{DirEntry, WalkDir};
+
+/// What is done with every matched file and its new name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Rename the file, see `FileSystemManager::move_file`
+    Move,
+    /// Duplicate the file under the new name, see `FileSystemManager::copy_file`
+    Copy,
+    /// Create a hard link with the new name, see `FileSystemManager::link_file`
+    Link,
+    /// Create a symbolic link with the new name, see `FileSystemManager::symlink_file`
+    Symlink(SymlinkTarget),
+    /// Append contents of the file to the file with the new name, see `FileSystemManager::append_file`
+    Append,
+}
+
+impl Action {
+    /// Verb used in messages, e.g. `Would copy file`
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Action::Move => "move",
+            Action::Copy => "copy",
+            Action::Link => "link",
+            Action::Symlink(_) => "symlink",
+            Action::Append => "append",
+        }
+    }
+}
+
+/// How symbolic links refer to the matched files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymlinkTarget {
+    /// Absolute path of the file, so the link may be moved anywhere
+    Absolute,
+    /// Path of the file relative to the directory of the link, so the link keeps working when the whole tree is moved
+    Relative,
+}
+
+impl FromStr for SymlinkTarget {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        match target {
+            "absolute" => Ok(SymlinkTarget::Absolute),
+            "relative" => Ok(SymlinkTarget::Relative),
+            _ => Err(format!(
+                "Unknown symlink target `{}`, expected absolute or relative",
+                target
+            )),
+        }
+    }
+}
+
 /// FSUTils struct is a simple wrap around std::fs module.
 /// It wraps up several std::fs methods like `std::fs::rename` and `std::fs::read_dir` and handles results of these methods
 pub struct FileSystemManager {
@@ -30,40 +90,78 @@ impl FileSystemManager {
     ///
     /// * `Vec<String>` of movable files
     ///
-
     fn is_hidden(entry: &DirEntry) -> bool {
-        entry
-            .file_name()
-            .to_str()
-            .map(|s| s.starts_with("."))
-            .unwrap_or(false)
+        entry.file_name().as_bytes().starts_with(b".")
     }
 
     /// Recursivly searches file in `self.current_dir` directory. Ignores hidden files.
     ///
     /// # Returns
-    /// * `Vec<String>` - relative pathes to all the files in `self.current` directory and it's subdirectories
-    pub fn get_filenames(&self) -> Vec<String> {
-        let walker = WalkDir::new(&self.current_dir).into_iter();
-        let mut result: Vec<String> = vec![];
-        for entry in walker.filter_entry(|e| !Self::is_hidden(e)) {
-            if entry.is_ok() {
-                result.push(entry.unwrap().path().to_str().unwrap().to_string());
-            }
-        }
-        result = result
+    /// * `Vec<PathBuf>` - relative pathes to all the files in `self.current` directory and it's subdirectories, including ones which are not valid UTF-8
+    pub fn get_filenames(&self) -> Vec<PathBuf> {
+        WalkDir::new(&self.current_dir)
             .into_iter()
-            .filter(|entry| std::fs::metadata(entry).unwrap().is_file())
-            .map(|file| {
-                file.strip_prefix(self.current_dir.to_str().unwrap())
-                    .unwrap()[1..]
-                    .to_string()
+            .filter_entry(|e| !Self::is_hidden(e))
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(&self.current_dir)
+                    .ok()
+                    .map(Path::to_path_buf)
             })
-            .collect();
-        result
+            .collect()
     }
 
-    /// Moves file located at `old_name` to the new location `new_name`. If the `self.force_overwrite` is set `True`, will overwrite excestant files. Otherwise returns `Err` after the attempt to overwrite a file
+    /// Recursivly searches directories in `self.current_dir` directory. Ignores hidden directories and `self.current_dir` itself.
+    ///
+    /// # Returns
+    /// * `Vec<PathBuf>` - relative pathes to all the directories in `self.current` directory and it's subdirectories
+    pub fn get_directories(&self) -> Vec<PathBuf> {
+        WalkDir::new(&self.current_dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|e| !Self::is_hidden(e))
+            .flatten()
+            .filter(|entry| entry.file_type().is_dir())
+            .filter_map(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(&self.current_dir)
+                    .ok()
+                    .map(Path::to_path_buf)
+            })
+            .collect()
+    }
+
+    /// Applies `action` to file located at `old_name` and its new location `new_name`
+    pub fn apply(&self, action: Action, old_name: &Path, new_name: &Path) -> Result<(), io::Error> {
+        match action {
+            Action::Move => self.move_file(old_name, new_name),
+            Action::Copy => self.copy_file(old_name, new_name),
+            Action::Link => self.link_file(old_name, new_name),
+            Action::Symlink(target) => self.symlink_file(old_name, new_name, target),
+            Action::Append => self.append_file(old_name, new_name),
+        }
+    }
+
+    /// Checks that `new_name` may be written, which is the case if it does not exist or `self.force_overwrite` is set. Dangling symbolic links count as existing files
+    fn check_overwrite(&self, new_name: &Path) -> Result<(), io::Error> {
+        if new_name.symlink_metadata().is_ok() && !self.force_overwrite {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "mmv: Not able to replace existing file: {}",
+                    new_name.display()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Moves file located at `old_name` to the new location `new_name`. If the `self.force_overwrite` is set `True`, will overwrite excestant files. Otherwise returns `Err` after the attempt to overwrite a file.
+    /// If `new_name` is on another filesystem, the file is copied and then removed, see `move_across_filesystems`
     ///
     /// # Arguments
     ///
@@ -73,27 +171,281 @@ impl FileSystemManager {
     /// # Returns
     ///
     /// * 'Result<(), std::io::Error>` containg either nothing or the error arised while file move
-    pub fn move_file(&self, old_name: &String, new_name: &String) -> Result<(), std::io::Error> {
-        if Path::new(new_name).exists() && !self.force_overwrite {
-            //eprintln!("mmv: Not able to replace existing file: {}", new_name);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::AlreadyExists,
-                "mmv: Not able to replace existing file: {}, new_name)",
-            ));
+    pub fn move_file(&self, old_name: &Path, new_name: &Path) -> Result<(), std::io::Error> {
+        if old_name == new_name {
+            return Ok(());
         }
-        let move_result = std::fs::rename(old_name, new_name);
-        println!("Moving file: {} -> {}", old_name.red(), new_name.green());
+        self.check_overwrite(new_name)?;
+        let move_result = match std::fs::rename(old_name, new_name) {
+            Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+                move_across_filesystems(old_name, new_name)
+            }
+            result => result,
+        };
+        println!(
+            "Moving file: {} -> {}",
+            old_name.display().to_string().red(),
+            new_name.display().to_string().green()
+        );
         move_result
     }
 
+    /// Copies file located at `old_name` to the new location `new_name`, keeping its permissions, access and modification times and extended attributes. Directories are copied with all their contents.
+    /// Overwrites existing files only if `self.force_overwrite` is set, just like `move_file`. An existing file is removed before copying rather than truncated, so links at `new_name` are replaced instead of written through.
+    /// Copying a file onto itself, e.g. to a link pointing to it, is an error
+    ///
+    /// # Returns
+    ///
+    /// * 'Result<(), std::io::Error>` containg either nothing or the error arised while file copy
+    pub fn copy_file(&self, old_name: &Path, new_name: &Path) -> Result<(), io::Error> {
+        if old_name == new_name {
+            return Ok(());
+        }
+        if same_file(old_name, new_name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "mmv: {} and {} are the same file",
+                    old_name.display(),
+                    new_name.display()
+                ),
+            ));
+        }
+        self.replace_target(new_name)?;
+        println!(
+            "Copying file: {} -> {}",
+            old_name.display().to_string().yellow(),
+            new_name.display().to_string().green()
+        );
+        if old_name.is_dir() {
+            return copy_tree(old_name, new_name);
+        }
+        copy_file_with_metadata(old_name, new_name).map(|_| ())
+    }
+
+    /// Creates a hard link `new_name` to the file located at `old_name`. Overwrites existing files only if `self.force_overwrite` is set, just like `move_file`
+    pub fn link_file(&self, old_name: &Path, new_name: &Path) -> Result<(), io::Error> {
+        if old_name == new_name {
+            return Ok(());
+        }
+        self.replace_target(new_name)?;
+        println!(
+            "Linking file: {} -> {}",
+            old_name.display().to_string().yellow(),
+            new_name.display().to_string().green()
+        );
+        fs::hard_link(old_name, new_name)
+    }
+
+    /// Creates a symbolic link `new_name` pointing to the file located at `old_name`, either with its absolute path or with path relative to the directory of the link.
+    /// Overwrites existing files only if `self.force_overwrite` is set, just like `move_file`
+    pub fn symlink_file(
+        &self,
+        old_name: &Path,
+        new_name: &Path,
+        target: SymlinkTarget,
+    ) -> Result<(), io::Error> {
+        if old_name == new_name {
+            return Ok(());
+        }
+        self.replace_target(new_name)?;
+        let absolute = clean(self.current_dir.join(old_name));
+        let link_target = match target {
+            SymlinkTarget::Absolute => absolute,
+            SymlinkTarget::Relative => {
+                let link_directory = clean(self.current_dir.join(new_name));
+                relative_path(link_directory.parent().unwrap_or(Path::new("/")), &absolute)
+            }
+        };
+        println!(
+            "Symlinking file: {} -> {}",
+            old_name.display().to_string().yellow(),
+            new_name.display().to_string().green()
+        );
+        std::os::unix::fs::symlink(link_target, new_name)
+    }
+
+    /// Appends contents of the file located at `old_name` to the end of `new_name`, which is created if it does not exist. The file at `old_name` is left as it is.
+    /// Several files may be appended to the same `new_name` one after another, so existing files are never treated as a conflict
+    pub fn append_file(&self, old_name: &Path, new_name: &Path) -> Result<(), io::Error> {
+        if old_name == new_name {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "mmv: Not able to append file to itself: {}",
+                    old_name.display()
+                ),
+            ));
+        }
+        println!(
+            "Appending file: {} -> {}",
+            old_name.display().to_string().yellow(),
+            new_name.display().to_string().green()
+        );
+        let mut source = File::open(old_name)?;
+        let mut target = File::options().append(true).create(true).open(new_name)?;
+        io::copy(&mut source, &mut target).map(|_| ())
+    }
+
+    /// Removes `new_name` if it exists and `self.force_overwrite` is set, as links, unlike `rename`, can not replace existing files, and copies must not write through links
+    fn replace_target(&self, new_name: &Path) -> Result<(), io::Error> {
+        self.check_overwrite(new_name)?;
+        match fs::remove_file(new_name) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Directories, which do not exist yet, but are needed for `new_names`, in order they should be created: every directory goes after its parent
+    pub fn missing_directories<'a>(
+        &self,
+        new_names: impl Iterator<Item = &'a Path>,
+    ) -> Vec<PathBuf> {
+        let mut missing: Vec<PathBuf> = vec![];
+        let mut recorded: HashSet<PathBuf> = HashSet::new();
+        for new_name in new_names {
+            // Ancestors of a recorded directory are recorded or exist already, so the walk up stops there
+            let mut chain: Vec<&Path> = new_name
+                .ancestors()
+                .skip(1)
+                .filter(|directory| !directory.as_os_str().is_empty())
+                .take_while(|directory| {
+                    !recorded.contains(*directory) && directory.symlink_metadata().is_err()
+                })
+                .collect();
+            chain.reverse();
+            for directory in chain {
+                recorded.insert(directory.to_path_buf());
+                missing.push(directory.to_path_buf());
+            }
+        }
+        missing
+    }
+
+    /// Creates a single directory, which parent must exist. Permissions are `mode` if it is given, otherwise they are defined by umask
+    pub fn create_directory(&self, directory: &Path, mode: Option<u32>) -> Result<(), io::Error> {
+        fs::create_dir(directory)?;
+        println!(
+            "Creating directory: {}",
+            directory.display().to_string().green()
+        );
+        if let Some(mode) = mode {
+            fs::set_permissions(directory, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    /// Removes directories created by `create_directory` in reverse order, as long as they are empty. Used to leave no trace of a failed run
+    pub fn remove_created_directories(&self, directories: &[PathBuf]) {
+        for directory in directories.iter().rev() {
+            if fs::remove_dir(directory).is_ok() {
+                println!(
+                    "Removing created directory: {}",
+                    directory.display().to_string().red()
+                );
+            }
+        }
+    }
+
+    /// Directories, which become empty after moving every file of `moves` to its new location, deepest first, so they may be removed in this order.
+    /// A directory becomes empty if each of its entries is either moved away or is a directory becoming empty itself, and nothing is moved into it.
+    /// Directories, which are empty already, are never listed, neither is `self.current_dir` or anything outside it
+    pub fn directories_emptied_by(&self, moves: &[(PathBuf, PathBuf)]) -> Vec<PathBuf> {
+        let targets: HashSet<&Path> = moves
+            .iter()
+            .map(|(_, new_name)| new_name.as_path())
+            .collect();
+        let vanishing: HashSet<&Path> = moves
+            .iter()
+            .map(|(file, _)| file.as_path())
+            .filter(|file| !targets.contains(file))
+            .collect();
+        let mut candidates: Vec<&Path> = moves
+            .iter()
+            .flat_map(|(file, _)| file.ancestors().skip(1))
+            .filter(|directory| !directory.as_os_str().is_empty() && directory.is_relative())
+            .collect::<HashSet<&Path>>()
+            .into_iter()
+            .collect();
+        candidates.sort_by_key(|directory| {
+            (
+                std::cmp::Reverse(directory.components().count()),
+                *directory,
+            )
+        });
+        // Targets and every directory containing some target receive files, so they are never emptied
+        let receiving: HashSet<&Path> = targets
+            .iter()
+            .flat_map(|target| target.ancestors())
+            .collect();
+        let mut emptied: Vec<PathBuf> = vec![];
+        let mut emptied_set: HashSet<PathBuf> = HashSet::new();
+        for directory in candidates {
+            if receiving.contains(directory) {
+                continue;
+            }
+            let Ok(entries) = fs::read_dir(directory) else {
+                continue;
+            };
+            let becomes_empty = entries.into_iter().all(|entry| {
+                entry.is_ok_and(|entry| {
+                    let path = directory.join(entry.file_name());
+                    vanishing.contains(path.as_path()) || emptied_set.contains(&path)
+                })
+            });
+            if becomes_empty {
+                emptied_set.insert(directory.to_path_buf());
+                emptied.push(directory.to_path_buf());
+            }
+        }
+        emptied
+    }
+
+    /// Removes `directories` listed by `directories_emptied_by` in their order. Directories, which turn out to be not empty, are kept
+    pub fn remove_empty_directories(&self, directories: &[PathBuf]) {
+        for directory in directories {
+            if fs::remove_dir(directory).is_ok() {
+                println!(
+                    "Removing empty directory: {}",
+                    directory.display().to_string().red()
+                );
+            }
+        }
+    }
+
     /// Converts file path so a simplified form. E.g. `../tmp/test/abc` becomes just `test/abs` in case `self.current_dir` ends with `tmp`.
-    pub fn normalize_path(&self, file: &str) -> String {
-        clean(Path::new(&self.current_dir).join(file))
-            .to_str()
-            .unwrap()
-            .to_string()
-            .strip_prefix(self.current_dir.to_str().unwrap())
-            .unwrap()[1..]
-            .to_string()
+    /// Pathes leading outside of `self.current_dir` are returned as cleaned absolute pathes. Works with names, which are not valid UTF-8
+    pub fn normalize_path(&self, file: &Path) -> PathBuf {
+        let cleaned = clean(self.current_dir.join(file));
+        match cleaned.strip_prefix(&self.current_dir) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => cleaned,
+        }
     }
 }
+
+/// Path leading to `path` from `directory`, e.g. `../data/a.txt` from `views` to `data/a.txt`. Both pathes must be absolute and clean
+fn relative_path(directory: &Path, path: &Path) -> PathBuf {
+    let directory: Vec<Component> = directory.components().collect();
+    let path: Vec<Component> = path.components().collect();
+    let common = directory
+        .iter()
+        .zip(&path)
+        .take_while(|(left, right)| left == right)
+        .count();
+    let mut relative: PathBuf = directory[common..].iter().map(|_| "..").collect();
+    relative.extend(&path[common..]);
+    relative
+}
+
+#[test]
+fn test_relative_path() {
+    assert_eq!(
+        relative_path(Path::new("/data/views/2026"), Path::new("/data/raw/a.txt")),
+        Path::new("../../raw/a.txt")
+    );
+    assert_eq!(
+        relative_path(Path::new("/data"), Path::new("/data/a.txt")),
+        Path::new("a.txt")
+    );
+}
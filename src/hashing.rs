@@ -0,0 +1,140 @@
+use md5::Md5;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Content hash, which may be used in templates, e.g. `#{sha256:12}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+    Md5,
+}
+
+/// Hex digests of a single file, computed only for the algorithms used by the template
+pub type FileDigests = HashMap<HashAlgorithm, String>;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+impl HashAlgorithm {
+    /// Returns algorithm named `name` or `None` if there is no such algorithm
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "md5" => Some(HashAlgorithm::Md5),
+            _ => None,
+        }
+    }
+
+    /// Length of the full hex digest
+    pub fn hex_length(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 64,
+            HashAlgorithm::Md5 => 32,
+        }
+    }
+}
+
+/// Computes hex digests of `reader` contents for all `algorithms` at once. Contents are read once by chunks, so large files are never loaded into memory, and every chunk is fed to each algorithm
+fn digest_all(algorithms: &[HashAlgorithm], mut reader: impl Read) -> std::io::Result<FileDigests> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut hashers: Vec<Hasher> = algorithms
+        .iter()
+        .map(|algorithm| Hasher::new(*algorithm))
+        .collect();
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for hasher in &mut hashers {
+            hasher.update(&buffer[..read]);
+        }
+    }
+    Ok(algorithms
+        .iter()
+        .copied()
+        .zip(hashers.into_iter().map(Hasher::finish))
+        .collect())
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Md5(Md5),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Md5(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        let bytes: Vec<u8> = match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+        };
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// Computes digests of `files` in parallel. Every file is read once, whatever number of algorithms is used
+///
+/// # Returns
+///
+/// Digests of every file in the same order as `files`, or the first error arised while reading them
+pub fn digest_files(
+    files: &[&Path],
+    algorithms: &[HashAlgorithm],
+) -> Result<Vec<FileDigests>, String> {
+    files
+        .par_iter()
+        .map(|file| {
+            File::open(file)
+                .and_then(|opened| digest_all(algorithms, opened))
+                .map_err(|err| format!("Could not compute hash of {}: {}", file.display(), err))
+        })
+        .collect()
+}
+
+#[test]
+fn test_digest() {
+    let algorithms = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Md5,
+        HashAlgorithm::Blake3,
+    ];
+    let digests = digest_all(&algorithms, "abc".as_bytes()).unwrap();
+    assert_eq!(digests.len(), 3);
+    assert_eq!(
+        digests[&HashAlgorithm::Sha256],
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        digests[&HashAlgorithm::Md5],
+        "900150983cd24fb0d6963f7d28e17f72"
+    );
+    assert_eq!(
+        digests[&HashAlgorithm::Blake3],
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+    );
+}
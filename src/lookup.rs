@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Table of `key -> value` pairs read from the first two columns of a CSV or TSV file, e.g. `DEP042,Accounting`.
+/// Columns are separated by tabs for `.tsv` files and for files, which first line contains a tab, and by commas otherwise.
+/// Fields may be quoted with `"`, a quote inside a quoted field is written as `""`. Empty lines and lines starting with `#` are skipped, further columns are ignored
+#[derive(Debug, Clone, PartialEq)]
+pub struct LookupTable {
+    entries: HashMap<String, String>,
+}
+
+/// What to do with a value, which is not a key of the table
+#[derive(Debug, Clone, PartialEq)]
+pub enum MissingKey {
+    /// Report an error, so nothing is moved
+    Error,
+    /// Leave the value as it is
+    Keep,
+    /// Replace the value with the given text
+    Default(String),
+}
+
+impl FromStr for MissingKey {
+    type Err = String;
+
+    /// Parses `error`, `keep` or `-TEXT` (default value, like in `#{1:-TEXT}`)
+    fn from_str(behaviour: &str) -> Result<Self, Self::Err> {
+        match behaviour {
+            "error" => Ok(MissingKey::Error),
+            "keep" => Ok(MissingKey::Keep),
+            _ => match behaviour.strip_prefix('-') {
+                Some(default) => Ok(MissingKey::Default(default.to_string())),
+                None => Err(format!(
+                    "Unknown missing key behaviour `{}`, expected error, keep or -DEFAULT",
+                    behaviour
+                )),
+            },
+        }
+    }
+}
+
+impl LookupTable {
+    /// Reads the table from `path`. Fails if the file can not be read, some line has less than two columns or some key is repeated
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read lookup table {}: {}", path.display(), err))?;
+        let tab_separated = path.extension().is_some_and(|extension| extension == "tsv")
+            || contents
+                .lines()
+                .next()
+                .is_some_and(|line| line.contains('\t'));
+        Self::parse(&contents, if tab_separated { '\t' } else { ',' })
+            .map_err(|err| format!("Lookup table {}: {}", path.display(), err))
+    }
+
+    fn parse(contents: &str, separator: char) -> Result<Self, String> {
+        let mut entries = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = split_fields(line, separator)
+                .map_err(|err| format!("line {}: {}", number + 1, err))?;
+            let [key, value, ..] = fields.as_slice() else {
+                return Err(format!(
+                    "line {}: expected a key and a value separated by `{}`",
+                    number + 1,
+                    separator.escape_default()
+                ));
+            };
+            if entries.insert(key.clone(), value.clone()).is_some() {
+                return Err(format!("line {}: key `{}` is repeated", number + 1, key));
+            }
+        }
+        Ok(LookupTable { entries })
+    }
+
+    /// Value for `key`, if there is one
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+}
+
+/// Splits a single CSV line into fields. Unquoted fields are trimmed, quoted ones are kept as they are
+fn split_fields(line: &str, separator: char) -> Result<Vec<String>, String> {
+    let mut fields = vec![];
+    let mut characters = line.chars().peekable();
+    loop {
+        while characters.next_if(|c| *c == ' ').is_some() {}
+        let mut field = String::new();
+        if characters.next_if_eq(&'"').is_some() {
+            loop {
+                match characters.next() {
+                    Some('"') if characters.next_if_eq(&'"').is_some() => field.push('"'),
+                    Some('"') => break,
+                    Some(character) => field.push(character),
+                    None => return Err("unterminated quoted field".to_string()),
+                }
+            }
+            while characters.next_if(|c| *c == ' ').is_some() {}
+            match characters.next() {
+                None => {
+                    fields.push(field);
+                    return Ok(fields);
+                }
+                Some(character) if character == separator => fields.push(field),
+                Some(character) => {
+                    return Err(format!("unexpected `{}` after quoted field", character))
+                }
+            }
+        } else {
+            let mut separated = false;
+            for character in characters.by_ref() {
+                if character == separator {
+                    separated = true;
+                    break;
+                }
+                field.push(character);
+            }
+            fields.push(field.trim().to_string());
+            if !separated {
+                return Ok(fields);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_lookup_table() {
+    let table = LookupTable::parse(
+        "# code,name\nDEP042, Accounting\n\"DEP,7\",\"Sales \"\"EU\"\"\",extra\n\nDEP001,\n\"DEP002\",\n",
+        ',',
+    )
+    .unwrap();
+    assert_eq!(table.get("DEP042"), Some("Accounting"));
+    assert_eq!(table.get("DEP,7"), Some("Sales \"EU\""));
+    assert_eq!(table.get("DEP001"), Some(""));
+    assert_eq!(table.get("DEP002"), Some(""));
+    assert_eq!(table.get("DEP999"), None);
+    let table = LookupTable::parse("a b\tc, d\r\n", '\t').unwrap();
+    assert_eq!(table.get("a b"), Some("c, d"));
+    assert!(LookupTable::parse("a,1\na,2", ',').is_err());
+    assert!(LookupTable::parse("lonely", ',').is_err());
+    assert!(LookupTable::parse("\"open,1", ',').is_err());
+    assert_eq!("-none".parse(), Ok(MissingKey::Default("none".to_string())));
+    assert!("skip".parse::<MissingKey>().is_err());
+}
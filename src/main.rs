@@ -3,9 +3,20 @@
 //! `mmv` is an CLI utility for convinient for renaming and moving files in a completly new, convinient way. It supports basic regular pattern matching for moving numerous amount of files in 1 short command
 
 mod cli;
+mod conflicts;
 mod controller;
+mod copying;
+mod dates;
+mod encoding;
 mod filesystem;
+mod hashing;
+mod lookup;
 mod matcher;
+mod metadata;
+mod sanitize;
+mod slug;
+mod sorting;
+mod template;
 use controller::MassMoveController;
 
 /// `mmv` entry point. See `Controller` documentaion for inside-view at the apllication infrastructure
@@ -1,5 +1,9 @@
+use crate::template::{FileContext, Template, TemplateError};
+use std::borrow::Cow;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
 pub struct Matcher {
-    pub pattern: String,
+    pub pattern: Vec<u8>,
 }
 
 impl Matcher {
@@ -7,153 +11,99 @@ impl Matcher {
     ///
     /// # Arguments
     ///
-    /// * pattern: `String` -- string containing stars, which will be used to filter filenames fitting the pattern
+    /// * pattern: `&[u8]` -- raw bytes of a pattern containing stars, which will be used to filter filenames fitting the pattern. Pattern, which is valid UTF-8, is stored in Unicode NFC form
     ///
-    pub fn new(pattern: String) -> Self {
-        Matcher { pattern }
+    pub fn new(pattern: &[u8]) -> Self {
+        Matcher {
+            pattern: Self::normalize(pattern).into_owned(),
+        }
+    }
+
+    /// Converts `name` to Unicode NFC form, so that e.g. `é` typed as a single character and `e` followed by a combining accent (as macOS stores names) are equal.
+    /// Names, which are not valid UTF-8, are left as they are
+    fn normalize(name: &[u8]) -> Cow<'_, [u8]> {
+        match std::str::from_utf8(name) {
+            Ok(text) if !is_nfc(text) => Cow::Owned(text.nfc().collect::<String>().into_bytes()),
+            _ => Cow::Borrowed(name),
+        }
+    }
+
+    /// Number of stars in `self.pattern`, which is the number of substrings any matched filename provides to a template
+    pub fn captures_count(&self) -> usize {
+        self.pattern.iter().filter(|byte| **byte == b'*').count()
     }
 
-    pub fn fill_in_template<'a>(
-        &'a self,
-        substituted_parts: Vec<&str>,
-        filename: &'a str,
-    ) -> String {
-        let mut result_string = filename.to_string();
-        substituted_parts
-            .iter()
-            .rev()
-            .enumerate()
-            .for_each(|(index, substring)| {
-                result_string = result_string.replace(
-                    &format!("#{w}", w = substituted_parts.len() - index),
-                    substring,
-                );
-            });
-        result_string
+    /// Parses rename template, checking it only refers to captures existing in `self.pattern`
+    ///
+    /// # Arguments
+    ///
+    /// * template: `&[u8]` -- raw bytes of the template, see `Template` for syntax
+    pub fn compile_template(&self, template: &[u8]) -> Result<Template, TemplateError> {
+        Template::parse(template, self.captures_count())
     }
 
-    /// Check if `file` matches `self.pattern`, meaning if it is possible to replace all stars in `self.pattern` with arbitrary to get `filename` string
+    /// Constructs new filename from `template`, replacing `#N` things with corresponding substituted parts
     ///
     /// # Arguments
     ///
-    /// * filename: `&str` -- filename to check
+    /// * file: `&FileContext` -- substrings returned by `pattern_matcher` and other per-file values
+    /// * template: `&Template` -- template compiled with `compile_template`
     ///
     /// # Returns
     ///
-    /// `None` in case filename does not fit `self.pattern`, Vec<String> of substituted substrings otherwise
-
-    pub fn pattern_matcher<'a>(&'a self, filename: &'a str) -> Option<Vec<String>> {
-        if self.pattern.len() > filename.len() {
-            return None;
-        }
-        let mut substituted_parts: Vec<String> = vec![];
-        let indexes_of_stars: Vec<usize> = self
-            .pattern
-            .char_indices()
-            .filter(|(_, ch)| ch == &'*')
-            .map(|(ind, _)| ind)
-            .collect();
-        let first_star = *indexes_of_stars.first().unwrap_or(&filename.len());
-        if first_star == filename.len() {
-            return if filename == self.pattern {
-                Some(vec![])
-            } else {
-                None
-            };
-        }
-        let substring_to_match = &self.pattern[0..first_star];
-        if !filename.is_char_boundary(first_star) {
-            return None;
-        }
-        let file_substring = &filename[0..first_star];
-        if substring_to_match != file_substring {
-            return None;
-        }
-        let stars_iterator = self
-            .pattern
-            .clone()
-            .char_indices()
-            .filter(|(_, ch)| ch == &'*')
-            .collect::<Vec<(usize, char)>>()
-            .into_iter()
-            .peekable();
-        let mut pattern_iterator = stars_iterator;
-        let mut filename_iterator = filename
-            .clone()
-            .char_indices()
-            .skip_while(|(ind, _)| ind < &first_star)
-            .collect::<Vec<(usize, char)>>()
-            .into_iter()
-            .peekable();
-        while pattern_iterator.peek().is_some() || filename_iterator.peek().is_some() {
-            if indexes_of_stars.contains(&(pattern_iterator.peek().unwrap().0 + 1))
-                || (pattern_iterator.peek().unwrap().0 == self.pattern.len() - 1)
-            {
-                let remaining_pattern: String = pattern_iterator
-                    .clone()
-                    .map(|(_, character)| character)
-                    .collect();
-                if remaining_pattern == "*".repeat(remaining_pattern.len()) {
-                    substituted_parts.push(
-                        filename_iterator
-                            .clone()
-                            .map(|(_, character)| character)
-                            .collect::<String>()
-                            .to_owned(),
-                    );
-                    break;
-                }
-            }
-            let substring_to_match = &self.pattern[pattern_iterator.peek().unwrap().0 + 1
-                ..pattern_iterator
-                    .clone()
-                    .nth(1)
-                    .unwrap_or((self.pattern.len(), '*'))
-                    .0];
-            let mut start_of_equal_substring = filename_iterator.clone();
-            while start_of_equal_substring.peek().is_some() {
-                let file_substring: String = start_of_equal_substring
-                    .clone()
-                    .take(substring_to_match.len())
-                    .map(|(_, character)| character)
-                    .collect();
-                if substring_to_match == file_substring {
-                    break;
-                }
-                start_of_equal_substring.next();
-            }
-            if start_of_equal_substring.peek().is_none() {
-                return None;
-            }
-            let file_substring: String = start_of_equal_substring
-                .clone()
-                .take(substring_to_match.len())
-                .map(|(_, character)| character)
-                .collect();
-            if substring_to_match != file_substring {
-                break;
-            }
-            let substituted_substring: String = filename_iterator
-                .clone()
-                .filter(|(ind, _)| ind < &start_of_equal_substring.peek().unwrap().0)
-                .map(|(_, character)| character)
-                .collect();
-
-            substituted_parts.push(substituted_substring);
+    /// `Err` in case template can not be evaluated for this file, raw bytes of the new filename otherwise
+    pub fn fill_in_template(
+        &self,
+        file: &FileContext,
+        template: &Template,
+    ) -> Result<Vec<u8>, TemplateError> {
+        template.render(file)
+    }
 
-            filename_iterator = start_of_equal_substring.clone();
-            for _ in 0..substring_to_match.len() {
-                filename_iterator.next();
-            }
-            pattern_iterator.next();
+    /// Check if `file` matches `self.pattern`, meaning if it is possible to replace all stars in `self.pattern` with arbitrary to get `filename` string.
+    /// Every star takes the shortest substring, which lets the rest of the pattern match, except the last one, which takes everything up to the pattern suffix.
+    /// Filename is compared as raw bytes, so names, which are not valid UTF-8, match too. Valid UTF-8 names are compared in Unicode NFC form, so names in NFD match patterns typed in NFC
+    ///
+    /// # Arguments
+    ///
+    /// * filename: `&[u8]` -- raw bytes of the filename to check
+    ///
+    /// # Returns
+    ///
+    /// `None` in case filename does not fit `self.pattern`, Vec of substituted substrings (in NFC form, if filename is valid UTF-8) otherwise
+    pub fn pattern_matcher(&self, filename: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let filename = Self::normalize(filename);
+        let literals: Vec<&[u8]> = self.pattern.split(|byte| *byte == b'*').collect();
+        let (prefix, rest) = literals.split_first().unwrap();
+        let Some((suffix, middle)) = rest.split_last() else {
+            return (filename.as_ref() == self.pattern.as_slice()).then(Vec::new);
+        };
+        let remaining = filename.strip_prefix(*prefix)?;
+        let mut remaining = remaining.strip_suffix(*suffix)?;
+        let mut substituted_parts: Vec<Vec<u8>> = vec![];
+        for literal in middle {
+            let start = find_bytes(remaining, literal)?;
+            substituted_parts.push(remaining[..start].to_vec());
+            remaining = &remaining[start + literal.len()..];
         }
+        substituted_parts.push(remaining.to_vec());
         Some(substituted_parts)
     }
 }
 
+/// Position of the first occurence of `needle` in `haystack`
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
 #[test]
 fn test_substitution() {
-    let matcher = Matcher::new("path/to/some_*_filename.*".to_string());
+    let matcher = Matcher::new(b"path/to/some_*_filename.*");
     let template = "path2/to/changed_#1_filename.#2";
     let filenames = vec![
         "path/to/some_A_filename.bin",
@@ -162,17 +112,30 @@ fn test_substitution() {
         "path/to/some_B_filename.jpg",
     ];
     for file in filenames {
-        let changes = matcher.pattern_matcher(file);
+        let changes = matcher.pattern_matcher(file.as_bytes());
         let file_letter = &file[13..14];
         let file_extension = &file[file.len() - 3..];
         assert!(changes.is_some());
-        assert!(dbg!(changes.clone()).unwrap() == vec![file_letter, file_extension]);
-        let new_name = matcher.fill_in_template(
-            changes.unwrap().iter().map(|s| s.as_str()).collect(),
-            template,
+        assert!(
+            dbg!(changes.clone()).unwrap()
+                == vec![file_letter.as_bytes(), file_extension.as_bytes()]
         );
+        let new_name = matcher
+            .fill_in_template(
+                &FileContext::new(
+                    std::path::Path::new(file),
+                    changes
+                        .as_ref()
+                        .unwrap()
+                        .iter()
+                        .map(Vec::as_slice)
+                        .collect(),
+                ),
+                &matcher.compile_template(template.as_bytes()).unwrap(),
+            )
+            .unwrap();
         assert_eq!(
-            dbg!(new_name),
+            dbg!(String::from_utf8(new_name).unwrap()),
             template
                 .replace("#1", file_letter)
                 .replace("#2", file_extension)
@@ -181,16 +144,45 @@ fn test_substitution() {
 }
 #[test]
 fn test_star_as_dirname() {
-    let matcher = Matcher::new("path/*".to_string());
-    let filename = "path/to/some/file";
+    let matcher = Matcher::new(b"path/*");
+    let filename = b"path/to/some/file";
     let changes = matcher.pattern_matcher(filename);
     assert!(changes.is_some());
 }
 
+#[test]
+fn test_normalized_match() {
+    let matcher = Matcher::new("cafe\u{301}_*.txt".as_bytes());
+    assert_eq!(
+        matcher.pattern_matcher("caf\u{e9}_cre\u{300}me.txt".as_bytes()),
+        Some(vec!["cr\u{e8}me".as_bytes().to_vec()])
+    );
+}
+
 #[test]
 fn test_no_star() {
-    let matcher = Matcher::new("abc".to_string());
-    let filename = "abc";
+    let matcher = Matcher::new(b"abc");
+    let filename = b"abc";
     let changes = matcher.pattern_matcher(filename);
     assert_eq!(changes, Some(vec![]))
 }
+
+#[test]
+fn test_raw_bytes_match() {
+    let matcher = Matcher::new(b"dir/*.txt");
+    // "Мир" in CP1251, which is not valid UTF-8
+    assert_eq!(
+        matcher.pattern_matcher(b"dir/\xcc\xe8\xf0.txt"),
+        Some(vec![b"\xcc\xe8\xf0".to_vec()])
+    );
+    let matcher = Matcher::new(b"a*c*");
+    assert_eq!(
+        matcher.pattern_matcher(b"abcbc"),
+        Some(vec![b"b".to_vec(), b"bc".to_vec()])
+    );
+    assert_eq!(
+        Matcher::new(b"a*c").pattern_matcher(b"abcbc"),
+        Some(vec![b"bcb".to_vec()])
+    );
+    assert_eq!(Matcher::new(b"ab*ba").pattern_matcher(b"aba"), None);
+}
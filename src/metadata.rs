@@ -0,0 +1,124 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Template token reading a property of the source file, e.g. `#{mtime:%Y-%m-%d}` or `#{size:human}`
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataToken {
+    /// Modification time, formatted with `strftime`-like format
+    Mtime(String),
+    /// Status change time, formatted with `strftime`-like format
+    Ctime(String),
+    /// Size in bytes
+    Size,
+    /// Size with a binary unit suffix, like `ls -h` prints it: `980`, `1.5K`, `12M`
+    HumanSize,
+    /// Name of the file owner, or numeric uid if there is no such user
+    Owner,
+    /// Permission bits in octal, e.g. `644`
+    Mode,
+}
+
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d";
+
+impl MetadataToken {
+    /// Parses metadata token with its optional argument (part after a colon)
+    ///
+    /// # Returns
+    ///
+    /// `None` if `name` is not a metadata token, `Some(Err)` if argument is invalid, parsed token otherwise
+    pub fn parse(name: &str, argument: Option<&str>) -> Option<Result<Self, String>> {
+        let token = match (name, argument) {
+            ("mtime", format) => Self::time_format(format).map(MetadataToken::Mtime),
+            ("ctime", format) => Self::time_format(format).map(MetadataToken::Ctime),
+            ("size", None) => Ok(MetadataToken::Size),
+            ("size", Some("human")) => Ok(MetadataToken::HumanSize),
+            ("owner", None) => Ok(MetadataToken::Owner),
+            ("mode", None) => Ok(MetadataToken::Mode),
+            ("size", Some(argument)) => Err(format!(
+                "Unknown `size` format `{}`, only `human` is supported",
+                argument
+            )),
+            ("owner" | "mode", Some(_)) => Err(format!("`{}` does not take arguments", name)),
+            _ => return None,
+        };
+        Some(token)
+    }
+
+    fn time_format(format: Option<&str>) -> Result<String, String> {
+        let format = format.unwrap_or(DEFAULT_TIME_FORMAT);
+        if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+            return Err(format!("Invalid time format `{}`", format));
+        }
+        Ok(format.to_string())
+    }
+
+    /// Reads the property from metadata of `path`
+    pub fn resolve(&self, path: &Path) -> Result<String, String> {
+        let metadata = fs::metadata(path)
+            .map_err(|err| format!("Could not read metadata of {}: {}", path.display(), err))?;
+        Ok(match self {
+            MetadataToken::Mtime(format) => {
+                Self::format_time(metadata.mtime(), metadata.mtime_nsec(), format)
+            }
+            MetadataToken::Ctime(format) => {
+                Self::format_time(metadata.ctime(), metadata.ctime_nsec(), format)
+            }
+            MetadataToken::Size => metadata.len().to_string(),
+            MetadataToken::HumanSize => human_size(metadata.len()),
+            MetadataToken::Owner => uzers::get_user_by_uid(metadata.uid())
+                .map(|user| user.name().to_string_lossy().to_string())
+                .unwrap_or_else(|| metadata.uid().to_string()),
+            MetadataToken::Mode => format!("{:o}", metadata.permissions().mode() & 0o7777),
+        })
+    }
+
+    fn format_time(seconds: i64, nanoseconds: i64, format: &str) -> String {
+        let time = UNIX_EPOCH + Duration::new(seconds.max(0) as u64, nanoseconds as u32);
+        DateTime::<Local>::from(time).format(format).to_string()
+    }
+}
+
+/// Formats `size` with a binary unit suffix. Values below 10 units keep one decimal digit
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut value = size as f64;
+    let mut unit = 0;
+    value /= 1024.0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 {
+        format!("{:.1}{}", value, UNITS[unit])
+    } else {
+        format!("{:.0}{}", value, UNITS[unit])
+    }
+}
+
+#[test]
+fn test_human_size() {
+    assert_eq!(human_size(980), "980");
+    assert_eq!(human_size(1536), "1.5K");
+    assert_eq!(human_size(12 * 1024 * 1024), "12M");
+}
+
+#[test]
+fn test_parse_metadata_token() {
+    assert_eq!(
+        MetadataToken::parse("mtime", Some("%Y%m%d")),
+        Some(Ok(MetadataToken::Mtime("%Y%m%d".to_string())))
+    );
+    assert_eq!(
+        MetadataToken::parse("size", Some("human")),
+        Some(Ok(MetadataToken::HumanSize))
+    );
+    assert!(MetadataToken::parse("ctime", Some("%Q")).unwrap().is_err());
+    assert!(MetadataToken::parse("stem", None).is_none());
+}
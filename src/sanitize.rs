@@ -0,0 +1,85 @@
+/// Characters, which are not allowed in filenames on Windows and FAT filesystems
+const RESERVED_CHARACTERS: [char; 8] = ['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+/// Names of devices, which can not be used as filenames on Windows, regardless of extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Maximal length of a single path component in bytes, common for most filesystems
+pub const MAX_COMPONENT_LENGTH: usize = 255;
+
+/// Makes every component of `path` portable across Linux, macOS and Windows filesystems. See `sanitize_component`
+pub fn sanitize_path(path: &str) -> String {
+    path.split('/')
+        .map(|component| match component {
+            "" | "." | ".." => component.to_string(),
+            _ => sanitize_component(component),
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Makes a single filename portable:
+/// * reserved characters `<>:"|?*\` and control characters are replaced with `_`
+/// * trailing dots and spaces are removed
+/// * reserved device names like `CON` or `com1.txt` get `_` appended to the stem
+/// * names longer than `MAX_COMPONENT_LENGTH` bytes are truncated, keeping the extension
+pub fn sanitize_component(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|character| {
+            if RESERVED_CHARACTERS.contains(&character) || character.is_control() {
+                '_'
+            } else {
+                character
+            }
+        })
+        .collect();
+    sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+    if sanitized.is_empty() {
+        sanitized.push('_');
+    }
+    let (stem, extension) = match sanitized.find('.') {
+        Some(dot) if dot > 0 => sanitized.split_at(dot),
+        _ => (sanitized.as_str(), ""),
+    };
+    if RESERVED_NAMES.contains(&stem.to_ascii_uppercase().as_str()) {
+        sanitized = format!("{}_{}", stem, extension);
+    }
+    if sanitized.len() > MAX_COMPONENT_LENGTH {
+        let extension = match sanitized.rfind('.') {
+            Some(dot) if dot > 0 && sanitized.len() - dot <= 16 => sanitized[dot..].to_string(),
+            _ => String::new(),
+        };
+        let mut stem_length = MAX_COMPONENT_LENGTH - extension.len();
+        while !sanitized.is_char_boundary(stem_length) {
+            stem_length -= 1;
+        }
+        sanitized = format!("{}{}", &sanitized[..stem_length], extension);
+    }
+    sanitized
+}
+
+#[test]
+fn test_sanitize_component() {
+    assert_eq!(
+        sanitize_component("what? <draft>: v1|2"),
+        "what_ _draft__ v1_2"
+    );
+    assert_eq!(sanitize_component("notes. . "), "notes");
+    assert_eq!(sanitize_component("con.tar.gz"), "con_.tar.gz");
+    assert_eq!(sanitize_component("LPT1"), "LPT1_");
+    assert_eq!(sanitize_component("console"), "console");
+    assert_eq!(sanitize_component("..."), "_");
+    let long = format!("{}.txt", "ы".repeat(200));
+    let sanitized = sanitize_component(&long);
+    assert!(sanitized.len() <= MAX_COMPONENT_LENGTH);
+    assert!(sanitized.ends_with("ы.txt"));
+}
+
+#[test]
+fn test_sanitize_path() {
+    assert_eq!(sanitize_path("a:b/../c?/d."), "a_b/../c_/d");
+}
@@ -0,0 +1,44 @@
+use deunicode::deunicode_with_tofu;
+
+/// Transliterates `text` to ASCII: Cyrillic, Greek and other scripts are replaced with their Latin spelling, accents are dropped.
+/// Characters, which have no transliteration (e.g. private use ones), are removed rather than replaced with `[?]`, which is not portable
+pub fn transliterate(text: &str) -> String {
+    deunicode_with_tofu(text, "")
+}
+
+/// Makes a portable slug of `text`: transliterates it, converts to lowercase and collapses every run of whitespace and punctuation into a single `separator`.
+/// Separators are never left at the beginning or at the end, e.g. `Отчёт за 2026 (финал)` becomes `otchiot-za-2026-final`
+pub fn slugify(text: &str, separator: &str) -> String {
+    let mut slug = String::new();
+    let mut pending_separator = false;
+    for character in transliterate(text).chars() {
+        if character.is_ascii_alphanumeric() {
+            if pending_separator && !slug.is_empty() {
+                slug.push_str(separator);
+            }
+            pending_separator = false;
+            slug.push(character.to_ascii_lowercase());
+        } else {
+            pending_separator = true;
+        }
+    }
+    slug
+}
+
+#[test]
+fn test_transliterate() {
+    assert_eq!(transliterate("Алёнка"), "Alionka");
+    assert_eq!(transliterate("Ελλάδα"), "Ellada");
+    assert_eq!(transliterate("Crème brûlée"), "Creme brulee");
+    assert_eq!(transliterate("c\u{e000}d"), "cd");
+}
+
+#[test]
+fn test_slugify() {
+    assert_eq!(
+        slugify("  Отчёт за 2026 (финал)! ", "-"),
+        "otchiot-za-2026-final"
+    );
+    assert_eq!(slugify("Crème   brûlée", "_"), "creme_brulee");
+    assert_eq!(slugify("...", "-"), "");
+}
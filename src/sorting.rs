@@ -0,0 +1,144 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Key to order matched files by. Ordering is needed to make numbering of files reproducible, as directory walk order depends on the filesystem
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    /// Plain lexicographic order of pathes
+    Name,
+    /// Lexicographic order, but digit runs are compared as numbers, so `file9` goes before `file10`
+    Natural,
+    /// Modification time, oldest first
+    Mtime,
+    /// File size, smallest first
+    Size,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        match key {
+            "name" => Ok(SortKey::Name),
+            "natural" => Ok(SortKey::Natural),
+            "mtime" => Ok(SortKey::Mtime),
+            "size" => Ok(SortKey::Size),
+            _ => Err(format!(
+                "Unknown sort key `{}`, expected one of: name, natural, mtime, size",
+                key
+            )),
+        }
+    }
+}
+
+impl SortKey {
+    /// Returns indexes of `files` in the order defined by this key. Ties are broken by the natural order of names, so the result is always deterministic.
+    /// Names, which are not valid UTF-8, are compared as raw bytes by `name` and with invalid bytes replaced by `U+FFFD` by `natural`.
+    /// Metadata and lossy names are read once per file rather than once per comparison, as directories may hold millions of files
+    pub fn order(&self, files: &[&Path]) -> Vec<usize> {
+        let entries: Vec<SortEntry> = files
+            .iter()
+            .map(|file| SortEntry::new(*self, file))
+            .collect();
+        let mut indexes: Vec<usize> = (0..files.len()).collect();
+        indexes.sort_by(|&left, &right| entries[left].compare(&entries[right]));
+        indexes
+    }
+}
+
+/// Everything a file is ordered by
+struct SortEntry<'a> {
+    key: EntryKey<'a>,
+    lossy_name: Cow<'a, str>,
+    name: &'a OsStr,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum EntryKey<'a> {
+    Name(&'a OsStr),
+    Natural,
+    Mtime(Option<SystemTime>),
+    Size(Option<u64>),
+}
+
+impl<'a> SortEntry<'a> {
+    fn new(key: SortKey, file: &'a Path) -> Self {
+        let key = match key {
+            SortKey::Name => EntryKey::Name(file.as_os_str()),
+            SortKey::Natural => EntryKey::Natural,
+            SortKey::Mtime => EntryKey::Mtime(
+                fs::metadata(file)
+                    .and_then(|metadata| metadata.modified())
+                    .ok(),
+            ),
+            SortKey::Size => EntryKey::Size(fs::metadata(file).map(|metadata| metadata.len()).ok()),
+        };
+        SortEntry {
+            key,
+            lossy_name: file.to_string_lossy(),
+            name: file.as_os_str(),
+        }
+    }
+
+    fn compare(&self, other: &Self) -> Ordering {
+        self.key
+            .cmp(&other.key)
+            .then_with(|| natural_compare(&self.lossy_name, &other.lossy_name))
+            .then_with(|| self.name.cmp(other.name))
+    }
+}
+
+/// Compares strings so that runs of digits are compared by their numeric value: `a2` < `a10`
+pub fn natural_compare(left: &str, right: &str) -> Ordering {
+    let (mut left_rest, mut right_rest) = (left, right);
+    loop {
+        let (Some(l), Some(r)) = (left_rest.chars().next(), right_rest.chars().next()) else {
+            return match (left_rest.is_empty(), right_rest.is_empty()) {
+                (true, true) => left.cmp(right),
+                (true, false) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+        };
+        if l.is_ascii_digit() && r.is_ascii_digit() {
+            let left_end = left_rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(left_rest.len());
+            let right_end = right_rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(right_rest.len());
+            let left_trimmed = left_rest[..left_end].trim_start_matches('0');
+            let right_trimmed = right_rest[..right_end].trim_start_matches('0');
+            let ordering = left_trimmed
+                .len()
+                .cmp(&right_trimmed.len())
+                .then_with(|| left_trimmed.cmp(right_trimmed));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            left_rest = &left_rest[left_end..];
+            right_rest = &right_rest[right_end..];
+        } else {
+            let ordering = l.cmp(&r);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            left_rest = &left_rest[l.len_utf8()..];
+            right_rest = &right_rest[r.len_utf8()..];
+        }
+    }
+}
+
+#[test]
+fn test_natural_compare() {
+    let mut files = vec!["file10", "file9", "file1", "file09b", "a/file2", "file"];
+    files.sort_by(|left, right| natural_compare(left, right));
+    assert_eq!(
+        files,
+        vec!["a/file2", "file", "file1", "file9", "file09b", "file10"]
+    );
+}
@@ -0,0 +1,1571 @@
+use crate::dates::DateReformat;
+use crate::hashing::{FileDigests, HashAlgorithm};
+use crate::lookup::{LookupTable, MissingKey};
+use crate::metadata::MetadataToken;
+use crate::slug::{slugify, transliterate};
+use crate::sorting::SortKey;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Error arised while parsing or rendering a rename template.
+/// `position` is a byte offset in the template string, pointing at the place which caused the error
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl TemplateError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        TemplateError {
+            message: message.into(),
+            position,
+        }
+    }
+
+    /// Formats error as the template string with a caret under the place which caused the error:
+    ///
+    /// ```text
+    /// a#{1 +}b
+    ///       ^ Expected a number, a capture or `(`
+    /// ```
+    pub fn pointing_at(&self, template: &[u8]) -> String {
+        let column = template
+            .get(..self.position)
+            .map_or(0, |prefix| String::from_utf8_lossy(prefix).chars().count());
+        format!(
+            "{}\n{}^ {}",
+            String::from_utf8_lossy(template),
+            " ".repeat(column),
+            self.message
+        )
+    }
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at position {})", self.message, self.position)
+    }
+}
+
+/// Parsed rename template. Template is parsed once, before any file is moved, and then rendered for every matched file.
+///
+/// Supported syntax:
+/// * `#N` -- replaced with the `N`-th star substring of the pattern
+/// * `#0`, `#{dir}`, `#{name}`, `#{stem}`, `#{ext}`, `#{parent}`, `#{parent:N}` -- parts of the matched path. See `PathToken`
+/// * `#{1[0:4]}`, `#{1:-unknown}`, `#{1/ /_}` -- slice of a capture, default value for an empty capture and regex replacement in a capture. See `CaptureToken`
+/// * `#{expression}` -- integer arithmetic on captures, e.g. `#{1 + 12}` or `#{(#1 * 2) - #2}`.
+///   Inside braces `#N` refers to a capture and bare numbers are constants. If expression has no `#N` at all, its first number names the capture, so `#{1 + 12}` means `#1 + 12`. Division of such a capture needs a space, `#{1 / 2}`, as `#{1/...}` is a replacement
+/// * `#{n}` or `#{n:start=1,step=1,width=4,reset=dir,sort=natural}` -- sequence number of the file. See `Counter` for options
+/// * `#{mtime:%Y-%m-%d}`, `#{ctime}`, `#{size}`, `#{size:human}`, `#{owner}`, `#{mode}` -- properties of the source file. See `MetadataToken`
+/// * `#{sha256:12}`, `#{blake3}`, `#{md5:8}` -- hex digest of the file contents, optionally truncated to the given number of characters
+/// * `#{shard}`, `#{shard:N}` -- first `N` (2 by default) hex characters of the SHA-256 digest, to spread files evenly over subdirectories
+/// * `#{bucket:SIZE}` -- sequence number of the file divided by `SIZE`, starting from 0, so every `SIZE` consecutive files share a value. Accepts further counter options, e.g. `#{bucket:1000,width=3}`
+/// * `#{...|pad:W}` -- any of the above, padded with zeros up to `W` characters
+/// * `#{...|translit}`, `#{...|slug}`, `#{...|slug:_}` -- any of the above, transliterated to ASCII or turned into a slug with the given separator (`-` by default)
+/// * `#{...|date:IN=>OUT}` -- any of the above, parsed as a date with `strftime`-like format `IN` and printed with format `OUT`
+/// * `#{...|map:FILE}`, `#{...|map:FILE:keep}`, `#{...|map:FILE:-DEFAULT}` -- any of the above, looked up in a CSV/TSV table, see `LookupTable`. A value missing in the table is an error, is kept or is replaced with `DEFAULT`
+/// * `#{?2:_v#2}`, `#{?2:THEN:ELSE}` -- conditional section, rendered for every file depending on a capture. See `Condition`
+pub struct Template {
+    segments: Vec<Segment>,
+    counters: Vec<Counter>,
+}
+
+/// Everything a template may need to know about a matched file to build its new name
+pub struct FileContext<'a> {
+    /// Path of the matched file, relative to the current directory
+    pub path: &'a Path,
+    /// Star substrings of the matched filename, in order of stars in the pattern. These are raw bytes, as filenames are not necessarily valid UTF-8
+    pub captures: Vec<&'a [u8]>,
+    /// Values of template counters for this file, in order of `Template::counters`
+    pub counters: Vec<i64>,
+    /// Digests of the file contents for every algorithm in `Template::hash_algorithms`
+    pub hashes: FileDigests,
+}
+
+impl<'a> FileContext<'a> {
+    pub fn new(path: &'a Path, captures: Vec<&'a [u8]>) -> Self {
+        FileContext {
+            path,
+            captures,
+            counters: vec![],
+            hashes: FileDigests::new(),
+        }
+    }
+}
+
+enum Segment {
+    Literal(Vec<u8>),
+    Capture(usize),
+    Token {
+        token: Token,
+        filters: Vec<Filter>,
+    },
+    Conditional {
+        condition: Condition,
+        then: Vec<Segment>,
+        otherwise: Vec<Segment>,
+    },
+}
+
+/// Condition of `#{?CONDITION:THEN:ELSE}` section. `THEN` and `ELSE` are templates themselves, `ELSE` may be omitted.
+/// Colons separating the parts are only those outside nested `#{...}`, a literal colon in any part is written as `\:`
+/// * `#{?2:...}` -- capture is not empty
+/// * `#{?!2:...}` -- capture is empty
+/// * `#{?2~REGEX:...}` -- capture contains a match of `REGEX`, `#{?!2~REGEX:...}` is the opposite
+pub struct Condition {
+    index: usize,
+    regex: Option<Regex>,
+    negated: bool,
+}
+
+enum Token {
+    Capture(CaptureToken),
+    Expression(Expression),
+    Counter(usize),
+    Metadata {
+        token: MetadataToken,
+        position: usize,
+    },
+    Hash {
+        algorithm: HashAlgorithm,
+        length: usize,
+    },
+    Path {
+        token: PathToken,
+        position: usize,
+    },
+}
+
+/// Capture with operations applied to it, in this order:
+/// * `#{1[2:5]}` -- characters from 2nd (inclusive) to 5th (exclusive). Both bounds may be omitted, negative bounds count from the end: `#{1[-3:]}`
+/// * `#{1/REGEX/REPLACEMENT}` -- replace all matches of `REGEX`, `REPLACEMENT` may refer to groups as `$1`. Slash inside `REGEX` is written as `\/`
+/// * `#{1:-DEFAULT}` -- `DEFAULT` if capture is empty
+///
+/// Operations may be combined as `#{1[0:4]/-/_:-none}`. Just `#{1}` is the same as `#1`
+pub struct CaptureToken {
+    index: usize,
+    slice: Option<(Option<i64>, Option<i64>)>,
+    replace: Option<(Regex, String)>,
+    default: Option<String>,
+}
+
+/// Part of the matched path. For `photos/2023/beach.tar.gz`:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathToken {
+    /// `#0` -- the whole path, `photos/2023/beach.tar.gz`
+    Full,
+    /// `#{dir}` -- directory of the file, `photos/2023`, or `.` for files in the current directory
+    Directory,
+    /// `#{name}` -- file name, `beach.tar.gz`
+    Name,
+    /// `#{stem}` -- file name without the last extension, `beach.tar`
+    Stem,
+    /// `#{ext}` -- the last extension without a dot, `gz`, or empty string
+    Extension,
+    /// `#{parent}` and `#{parent:N}` -- name of the `N`-th directory above the file, `2023` for `N = 1`, `photos` for `N = 2`
+    Parent(usize),
+}
+
+/// Sequence counter token. Counter value is assigned to every matched file before any of them is renamed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counter {
+    /// Value of the first file
+    pub start: i64,
+    /// Difference between values of consecutive files
+    pub step: i64,
+    /// Minimal number of digits, shorter values are padded with zeros
+    pub width: usize,
+    /// Restart numbering in every directory
+    pub per_directory: bool,
+    /// Order in which files are numbered. `None` means the order of matched files list, see `--sort`
+    pub order: Option<SortKey>,
+    /// Number of consecutive files sharing the same value, 1 for plain counters and `SIZE` for `#{bucket:SIZE}`
+    pub size: i64,
+    /// Position of the token in the template, for error reports
+    position: usize,
+}
+
+enum Filter {
+    Pad(usize),
+    Transliterate,
+    Slug(String),
+    Map {
+        table: LookupTable,
+        missing: MissingKey,
+        position: usize,
+    },
+    Date {
+        reformat: DateReformat,
+        position: usize,
+    },
+}
+
+enum Expression {
+    Number(i64),
+    Capture {
+        index: usize,
+        position: usize,
+    },
+    Negate {
+        inner: Box<Expression>,
+        position: usize,
+    },
+    Binary {
+        operator: char,
+        left: Box<Expression>,
+        right: Box<Expression>,
+        position: usize,
+    },
+}
+
+impl Template {
+    /// Parses `source` template.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` -- raw bytes of the template. Literal text may contain bytes, which are not valid UTF-8, and they are copied to new names as they are, while `#{...}` must be valid UTF-8
+    /// * `captures_count` -- number of stars in the pattern. Referencing a capture, which does not exist, is an error
+    ///
+    /// # Returns
+    ///
+    /// `Err` describing the first syntax error in `source`, parsed `Template` otherwise
+    pub fn parse(source: &[u8], captures_count: usize) -> Result<Self, TemplateError> {
+        let mut counters = vec![];
+        let segments = parse_segments(
+            source,
+            0,
+            source.len(),
+            captures_count,
+            &mut counters,
+            false,
+        )?;
+        Ok(Template { segments, counters })
+    }
+
+    /// Counter tokens used in the template, in order of appearance
+    pub fn counters(&self) -> &[Counter] {
+        &self.counters
+    }
+
+    /// Hash algorithms used in the template. Files are hashed only if this list is not empty
+    pub fn hash_algorithms(&self) -> Vec<HashAlgorithm> {
+        fn collect(segments: &[Segment], algorithms: &mut Vec<HashAlgorithm>) {
+            for segment in segments {
+                match segment {
+                    Segment::Token {
+                        token: Token::Hash { algorithm, .. },
+                        ..
+                    } if !algorithms.contains(algorithm) => algorithms.push(*algorithm),
+                    Segment::Conditional {
+                        then, otherwise, ..
+                    } => {
+                        collect(then, algorithms);
+                        collect(otherwise, algorithms);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut algorithms = vec![];
+        collect(&self.segments, &mut algorithms);
+        algorithms
+    }
+
+    /// Builds a new filename, substituting data of a matched file into the template
+    ///
+    /// # Arguments
+    ///
+    /// * `file` -- captures and other per-file values
+    ///
+    /// # Returns
+    ///
+    /// `Err` if some expression can not be evaluated for this file (e.g. capture is not a number), raw bytes of the new filename otherwise.
+    /// Captures and path parts are copied byte for byte, so names which are not valid UTF-8 survive renaming. Operations on text (slices, regexes, arithmetic, filters) see such names with invalid bytes replaced by `U+FFFD`
+    pub fn render(&self, file: &FileContext) -> Result<Vec<u8>, TemplateError> {
+        self.render_segments(&self.segments, file)
+    }
+
+    fn render_segments(
+        &self,
+        segments: &[Segment],
+        file: &FileContext,
+    ) -> Result<Vec<u8>, TemplateError> {
+        let mut result = vec![];
+        for segment in segments {
+            match segment {
+                Segment::Conditional {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    let branch = if condition.holds(file.captures[condition.index - 1]) {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    result.extend(self.render_segments(branch, file)?);
+                }
+                Segment::Literal(text) => result.extend_from_slice(text),
+                Segment::Capture(index) => result.extend_from_slice(file.captures[index - 1]),
+                Segment::Token { token, filters } => {
+                    let value = match token {
+                        Token::Capture(capture) => capture.apply(file.captures[capture.index - 1]),
+                        Token::Expression(expression) => expression
+                            .evaluate(&file.captures)?
+                            .to_string()
+                            .into_bytes(),
+                        Token::Counter(index) => Filter::Pad(self.counters[*index].width)
+                            .apply(file.counters[*index].to_string())?
+                            .into_bytes(),
+                        Token::Metadata { token, position } => token
+                            .resolve(file.path)
+                            .map_err(|message| TemplateError::new(message, *position))?
+                            .into_bytes(),
+                        Token::Path { token, position } => token
+                            .resolve(file.path)
+                            .map_err(|message| TemplateError::new(message, *position))?,
+                        Token::Hash { algorithm, length } => {
+                            file.hashes[algorithm].as_bytes()[..*length].to_vec()
+                        }
+                    };
+                    if filters.is_empty() {
+                        result.extend(value);
+                        continue;
+                    }
+                    let mut value = String::from_utf8_lossy(&value).into_owned();
+                    for filter in filters {
+                        value = filter.apply(value)?;
+                    }
+                    result.extend_from_slice(value.as_bytes());
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl Condition {
+    fn holds(&self, capture: &[u8]) -> bool {
+        let holds = match &self.regex {
+            Some(regex) => regex.is_match(&String::from_utf8_lossy(capture)),
+            None => !capture.is_empty(),
+        };
+        holds != self.negated
+    }
+}
+
+impl CaptureToken {
+    /// Parses `#{...}` contents, which start with a capture index, followed by operations
+    ///
+    /// # Returns
+    ///
+    /// `None` if `subject` is not a capture with operations (e.g. it is an arithmetic expression), `Some(Err)` if operations are invalid, parsed token otherwise
+    fn parse(
+        subject: &str,
+        position: usize,
+        captures_count: usize,
+    ) -> Option<Result<Self, TemplateError>> {
+        let digits_end = subject
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(subject.len());
+        let mut rest = &subject[digits_end..];
+        let is_capture = digits_end > 0
+            && (rest.is_empty()
+                || rest.starts_with('[')
+                || rest.starts_with(":-")
+                || (rest.starts_with('/') && split_unescaped(&rest[1..], b'/').is_some()));
+        if !is_capture {
+            return None;
+        }
+        let error = |message: String| Some(Err(TemplateError::new(message, position)));
+        let index = match subject[..digits_end].parse::<usize>() {
+            Ok(index) if index >= 1 && index <= captures_count => index,
+            _ => {
+                return error(format!(
+                    "Capture #{} does not exist, pattern has only {} stars",
+                    &subject[..digits_end],
+                    captures_count
+                ))
+            }
+        };
+        let mut token = CaptureToken {
+            index,
+            slice: None,
+            replace: None,
+            default: None,
+        };
+        if let Some(slice) = rest.strip_prefix('[') {
+            let Some((bounds, after)) = slice.split_once(']') else {
+                return error("Expected `]` after slice bounds".to_string());
+            };
+            let Some((from, to)) = bounds.split_once(':') else {
+                return error(format!(
+                    "Slice must look like `[from:to]`, not `[{}]`",
+                    bounds
+                ));
+            };
+            let parse_bound = |bound: &str| match bound.trim() {
+                "" => Ok(None),
+                bound => bound.parse::<i64>().map(Some),
+            };
+            match (parse_bound(from), parse_bound(to)) {
+                (Ok(from), Ok(to)) => token.slice = Some((from, to)),
+                _ => return error(format!("Invalid slice bounds `[{}]`", bounds)),
+            }
+            rest = after;
+        }
+        if let Some(replace) = rest.strip_prefix('/') {
+            let Some((pattern, after)) = split_unescaped(replace, b'/') else {
+                return error("Replacement must look like `/REGEX/REPLACEMENT`".to_string());
+            };
+            let (replacement, after) = match after.find(":-") {
+                Some(default) => (&after[..default], &after[default..]),
+                None => (after, ""),
+            };
+            match Regex::new(&unescape(pattern, &['/', '|'])) {
+                Ok(regex) => token.replace = Some((regex, unescape(replacement, &['/', '|']))),
+                Err(err) => return error(format!("Invalid regex `{}`: {}", pattern, err)),
+            }
+            rest = after;
+        }
+        if let Some(default) = rest.strip_prefix(":-") {
+            token.default = Some(unescape(default, &['|']));
+            rest = "";
+        }
+        if !rest.is_empty() {
+            return error(format!("Unexpected `{}` after capture #{}", rest, index));
+        }
+        Some(Ok(token))
+    }
+
+    fn apply(&self, capture: &[u8]) -> Vec<u8> {
+        if capture.is_empty() {
+            if let Some(default) = &self.default {
+                return default.as_bytes().to_vec();
+            }
+        }
+        if self.slice.is_none() && self.replace.is_none() {
+            return capture.to_vec();
+        }
+        let mut value = String::from_utf8_lossy(capture).into_owned();
+        if let Some((from, to)) = self.slice {
+            let chars: Vec<char> = value.chars().collect();
+            let length = chars.len() as i64;
+            let bound = |bound: i64| {
+                if bound < 0 {
+                    (length + bound).max(0) as usize
+                } else {
+                    bound.min(length) as usize
+                }
+            };
+            let from = bound(from.unwrap_or(0));
+            let to = bound(to.unwrap_or(length));
+            value = chars[from..to.max(from)].iter().collect();
+        }
+        if let Some((regex, replacement)) = &self.replace {
+            value = regex.replace_all(&value, replacement.as_str()).to_string();
+        }
+        match &self.default {
+            Some(default) if value.is_empty() => default.as_bytes().to_vec(),
+            _ => value.into_bytes(),
+        }
+    }
+}
+
+/// Removes backslashes escaping any of `characters`, e.g. `a\|b` becomes `a|b`. Other escapes, which mean something to regexes, are kept as they are
+fn unescape(text: &str, characters: &[char]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(character) = chars.next() {
+        if character != '\\' {
+            result.push(character);
+            continue;
+        }
+        match chars.next() {
+            Some(next) if characters.contains(&next) => result.push(next),
+            Some(next) => {
+                result.push(character);
+                result.push(next);
+            }
+            None => result.push(character),
+        }
+    }
+    result
+}
+
+/// Splits `text` at the first `separator`, which is neither escaped with a backslash nor located inside nested braces
+fn split_unescaped(text: &str, separator: u8) -> Option<(&str, &str)> {
+    let index = find_unescaped(text.as_bytes(), separator)?;
+    Some((&text[..index], &text[index + 1..]))
+}
+
+/// Byte index of the first `separator` in `text`, which is neither escaped with a backslash nor located inside nested braces
+fn find_unescaped(text: &[u8], separator: u8) -> Option<usize> {
+    let mut escaped = false;
+    let mut depth = 0;
+    for (index, byte) in text.iter().enumerate() {
+        if !escaped {
+            match byte {
+                _ if *byte == separator && depth == 0 => return Some(index),
+                b'{' => depth += 1,
+                b'}' => depth -= 1,
+                _ => {}
+            }
+        }
+        escaped = *byte == b'\\' && !escaped;
+    }
+    None
+}
+
+impl PathToken {
+    /// Parses path token with its optional argument (part after a colon)
+    ///
+    /// # Returns
+    ///
+    /// `None` if `name` is not a path token, `Some(Err)` if argument is invalid, parsed token otherwise
+    pub fn parse(name: &str, argument: Option<&str>) -> Option<Result<Self, String>> {
+        let token = match (name, argument) {
+            ("dir", None) => Ok(PathToken::Directory),
+            ("name", None) => Ok(PathToken::Name),
+            ("stem", None) => Ok(PathToken::Stem),
+            ("ext", None) => Ok(PathToken::Extension),
+            ("parent", None) => Ok(PathToken::Parent(1)),
+            ("parent", Some(level)) => match level.parse::<usize>() {
+                Ok(level) if level >= 1 => Ok(PathToken::Parent(level)),
+                _ => Err(format!(
+                    "`parent` level must be a positive number, not `{}`",
+                    level
+                )),
+            },
+            ("dir" | "name" | "stem" | "ext", Some(_)) => {
+                Err(format!("`{}` does not take arguments", name))
+            }
+            _ => return None,
+        };
+        Some(token)
+    }
+
+    /// Extracts raw bytes of this part from `path`, which must be normalized
+    pub fn resolve(&self, path: &Path) -> Result<Vec<u8>, String> {
+        let bytes = path.as_os_str().as_bytes();
+        let (directory, name) = match rsplit_once(bytes, b'/') {
+            Some((directory, name)) => (Some(directory), name),
+            None => (None, bytes),
+        };
+        let (stem, extension) = match rsplit_once(name, b'.') {
+            Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+            _ => (name, &b""[..]),
+        };
+        Ok(match self {
+            PathToken::Full => bytes,
+            PathToken::Directory => directory.unwrap_or(b"."),
+            PathToken::Name => name,
+            PathToken::Stem => stem,
+            PathToken::Extension => extension,
+            PathToken::Parent(level) => directory
+                .and_then(|directory| directory.rsplit(|byte| *byte == b'/').nth(level - 1))
+                .ok_or_else(|| {
+                    format!(
+                        "{} has no parent directory {} levels up",
+                        path.display(),
+                        level
+                    )
+                })?,
+        }
+        .to_vec())
+    }
+}
+
+/// Splits `bytes` at the last `separator`
+fn rsplit_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().rposition(|byte| *byte == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+impl Counter {
+    /// Assigns counter values to `files`
+    ///
+    /// # Returns
+    ///
+    /// `Err` if some value does not fit into a 64-bit integer, value for every file in the same order as `files` otherwise
+    pub fn number(&self, files: &[&Path]) -> Result<Vec<i64>, TemplateError> {
+        let mut values = vec![0; files.len()];
+        let mut seen_in_directory: HashMap<&Path, i64> = HashMap::new();
+        let mut seen = 0;
+        let order = match self.order {
+            Some(key) => key.order(files),
+            None => (0..files.len()).collect(),
+        };
+        for index in order {
+            let position = if self.per_directory {
+                let directory = files[index].parent().unwrap_or(Path::new(""));
+                let count = seen_in_directory.entry(directory).or_insert(0);
+                *count += 1;
+                *count - 1
+            } else {
+                seen += 1;
+                seen - 1
+            };
+            values[index] = self
+                .step
+                .checked_mul(position / self.size)
+                .and_then(|offset| self.start.checked_add(offset))
+                .ok_or_else(|| {
+                    TemplateError::new(
+                        format!(
+                            "Counter value {} + {} * {} does not fit into a 64-bit integer",
+                            self.start,
+                            self.step,
+                            position / self.size
+                        ),
+                        self.position,
+                    )
+                })?;
+        }
+        Ok(values)
+    }
+}
+
+impl Filter {
+    fn apply(&self, value: String) -> Result<String, TemplateError> {
+        Ok(match self {
+            Filter::Pad(width) => match value.strip_prefix('-') {
+                Some(digits) => format!("-{:0>width$}", digits, width = width.saturating_sub(1)),
+                None => format!("{:0>width$}", value, width = width),
+            },
+            Filter::Transliterate => transliterate(&value),
+            Filter::Slug(separator) => slugify(&value, separator),
+            Filter::Map {
+                table,
+                missing,
+                position,
+            } => match (table.get(&value), missing) {
+                (Some(mapped), _) => mapped.to_string(),
+                (None, MissingKey::Keep) => value,
+                (None, MissingKey::Default(default)) => default.clone(),
+                (None, MissingKey::Error) => {
+                    return Err(TemplateError::new(
+                        format!("`{}` is not found in the lookup table", value),
+                        *position,
+                    ))
+                }
+            },
+            Filter::Date { reformat, position } => reformat
+                .apply(&value)
+                .map_err(|message| TemplateError::new(message, *position))?,
+        })
+    }
+}
+
+impl Expression {
+    fn evaluate(&self, captures: &[&[u8]]) -> Result<i64, TemplateError> {
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::Capture { index, position } => {
+                let capture = String::from_utf8_lossy(captures[index - 1]);
+                capture.parse::<i64>().map_err(|_| {
+                    TemplateError::new(
+                        format!("Capture #{} is `{}`, which is not a number", index, capture),
+                        *position,
+                    )
+                })
+            }
+            Expression::Negate { inner, position } => {
+                let value = inner.evaluate(captures)?;
+                value.checked_neg().ok_or_else(|| {
+                    TemplateError::new(format!("Can not evaluate -({})", value), *position)
+                })
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+                position,
+            } => {
+                let left = left.evaluate(captures)?;
+                let right = right.evaluate(captures)?;
+                let result = match operator {
+                    '+' => left.checked_add(right),
+                    '-' => left.checked_sub(right),
+                    '*' => left.checked_mul(right),
+                    '/' => left.checked_div(right),
+                    _ => left.checked_rem(right),
+                };
+                result.ok_or_else(|| {
+                    TemplateError::new(
+                        format!("Can not evaluate {} {} {}", left, operator, right),
+                        *position,
+                    )
+                })
+            }
+        }
+    }
+
+    fn has_captures(&self) -> bool {
+        match self {
+            Expression::Number(_) => false,
+            Expression::Capture { .. } => true,
+            Expression::Negate { inner, .. } => inner.has_captures(),
+            Expression::Binary { left, right, .. } => left.has_captures() || right.has_captures(),
+        }
+    }
+
+    /// Turns the leftmost number into a capture reference. Used for expressions like `#{1 + 12}`, which do not use `#N` explicitly.
+    /// `position` is the offset of the expression, errors of the capture point at it
+    fn capture_first_number(
+        &mut self,
+        captures_count: usize,
+        position: usize,
+    ) -> Result<bool, TemplateError> {
+        match self {
+            Expression::Number(value) => {
+                let index = *value as usize;
+                if index < 1 || index > captures_count {
+                    return Err(TemplateError::new(
+                        format!(
+                            "Capture #{} does not exist, pattern has only {} stars",
+                            value, captures_count
+                        ),
+                        position,
+                    ));
+                }
+                *self = Expression::Capture { index, position };
+                Ok(true)
+            }
+            Expression::Capture { .. } => Ok(false),
+            Expression::Negate { inner, .. } => {
+                inner.capture_first_number(captures_count, position)
+            }
+            Expression::Binary { left, right, .. } => Ok(left
+                .capture_first_number(captures_count, position)?
+                || right.capture_first_number(captures_count, position)?),
+        }
+    }
+}
+
+/// Parses `source[start..end]` into segments. Positions of errors are byte offsets in the whole `source`
+///
+/// # Arguments
+///
+/// * `counters` -- counter tokens found so far, new counters are appended to it
+/// * `in_branch` -- whether segments are a branch of a conditional section, where colon has to be escaped as `\:`
+fn parse_segments(
+    source: &[u8],
+    start: usize,
+    end: usize,
+    captures_count: usize,
+    counters: &mut Vec<Counter>,
+    in_branch: bool,
+) -> Result<Vec<Segment>, TemplateError> {
+    let mut segments = vec![];
+    let mut literal: Vec<u8> = vec![];
+    let mut position = start;
+    while position < end {
+        let rest = &source[position..end];
+        if rest.starts_with(b"#{") {
+            let closing = find_closing_brace(&source[..end], position + 1)
+                .ok_or_else(|| TemplateError::new("Unclosed `#{` in template", position))?;
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(parse_braced(
+                source,
+                position + 2,
+                closing,
+                captures_count,
+                counters,
+            )?);
+            position = closing + 1;
+        } else if rest.starts_with(b"#0") {
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Token {
+                token: Token::Path {
+                    token: PathToken::Full,
+                    position,
+                },
+                filters: vec![],
+            });
+            position += 2;
+        } else if rest.starts_with(b"#") && rest.get(1).is_some_and(u8::is_ascii_digit) {
+            let digits: String = rest[1..]
+                .iter()
+                .take_while(|byte| byte.is_ascii_digit())
+                .map(|byte| *byte as char)
+                .collect();
+            // Longest prefix naming an existing capture wins, so `#12` is a 12th capture only if pattern has 12 stars
+            let taken = (1..=digits.len())
+                .rev()
+                .find(|len| {
+                    digits[..*len]
+                        .parse::<usize>()
+                        .map(|index| index >= 1 && index <= captures_count)
+                        .unwrap_or(false)
+                })
+                .ok_or_else(|| {
+                    TemplateError::new(
+                        format!(
+                            "Capture #{} does not exist, pattern has only {} stars",
+                            digits, captures_count
+                        ),
+                        position,
+                    )
+                })?;
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Capture(digits[..taken].parse().unwrap()));
+            position += 1 + taken;
+        } else if rest.starts_with(b"\\:") && in_branch {
+            literal.push(b':');
+            position += 2;
+        } else {
+            literal.push(rest[0]);
+            position += 1;
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Returns byte index of `}` closing the `{` located at `open`
+fn find_closing_brace(source: &[u8], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, byte) in source[open..].iter().enumerate() {
+        match byte {
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses contents of `#{...}`, located at `source[start..end]`
+fn parse_braced(
+    source: &[u8],
+    start: usize,
+    end: usize,
+    captures_count: usize,
+    counters: &mut Vec<Counter>,
+) -> Result<Segment, TemplateError> {
+    if source[start..end].starts_with(b"?") {
+        return parse_conditional(source, start + 1, end, captures_count, counters);
+    }
+    let body = text(source, start, end)?;
+    let mut parts = vec![];
+    let mut rest = body;
+    while let Some((part, after)) = split_unescaped(rest, b'|') {
+        parts.push(part);
+        rest = after;
+    }
+    parts.push(rest);
+    let mut parts = parts.into_iter();
+    let subject = parts.next().unwrap();
+    let (name, argument) = match subject.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (subject, None),
+    };
+    let token = if let Some(capture) = CaptureToken::parse(subject, start, captures_count) {
+        Token::Capture(capture?)
+    } else if name == "n" {
+        counters.push(parse_counter(argument.unwrap_or(""), start + 1)?);
+        Token::Counter(counters.len() - 1)
+    } else if name == "bucket" {
+        let (size, options) = match argument {
+            Some(argument) => argument.split_once(',').unwrap_or((argument, "")),
+            None => {
+                return Err(TemplateError::new(
+                    "`bucket` expects the number of files per bucket, e.g. `#{bucket:1000}`",
+                    start,
+                ))
+            }
+        };
+        counters.push(parse_counter(
+            &format!("start=0,size={},{}", size, options),
+            start + 1,
+        )?);
+        Token::Counter(counters.len() - 1)
+    } else if name == "shard" {
+        let algorithm = HashAlgorithm::Sha256;
+        let length = parse_hash_length(name, algorithm, argument, 2, start)?;
+        Token::Hash { algorithm, length }
+    } else if let Some(algorithm) = HashAlgorithm::from_name(name) {
+        let length = parse_hash_length(name, algorithm, argument, algorithm.hex_length(), start)?;
+        Token::Hash { algorithm, length }
+    } else if let Some(token) = PathToken::parse(name, argument) {
+        Token::Path {
+            token: token.map_err(|message| TemplateError::new(message, start))?,
+            position: start,
+        }
+    } else if let Some(token) = MetadataToken::parse(name, argument) {
+        Token::Metadata {
+            token: token.map_err(|message| TemplateError::new(message, start))?,
+            position: start,
+        }
+    } else {
+        let mut expression = ExpressionParser::new(subject, start, captures_count).parse()?;
+        if !expression.has_captures() {
+            // `#{1/2/3}` replaces `2` with `3` in capture #1, so `#{1/2}` dividing it would make the meaning of `/` depend on what follows
+            let digits = subject
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(subject.len());
+            if digits > 0 && subject[digits..].starts_with('/') {
+                return Err(TemplateError::new(
+                    format!(
+                        "Write `#{0} /` to divide capture #{0}, `/` right after a capture number starts a replacement `#{{{0}/REGEX/REPLACEMENT}}`",
+                        &subject[..digits]
+                    ),
+                    start + digits,
+                ));
+            }
+            expression.capture_first_number(captures_count, start)?;
+        }
+        Token::Expression(expression)
+    };
+    let mut filters = vec![];
+    let mut offset = start + subject.len() + 1;
+    for part in parts {
+        filters.push(parse_filter(part.trim(), offset)?);
+        offset += part.len() + 1;
+    }
+    Ok(Segment::Token { token, filters })
+}
+
+/// Parses length of a hex digest used by token `name`, e.g. `12` in `#{sha256:12}`, which is `default` if `argument` is omitted
+fn parse_hash_length(
+    name: &str,
+    algorithm: HashAlgorithm,
+    argument: Option<&str>,
+    default: usize,
+    position: usize,
+) -> Result<usize, TemplateError> {
+    let Some(length) = argument else {
+        return Ok(default);
+    };
+    length
+        .parse::<usize>()
+        .ok()
+        .filter(|length| (1..=algorithm.hex_length()).contains(length))
+        .ok_or_else(|| {
+            TemplateError::new(
+                format!(
+                    "`{}` length must be a number from 1 to {}",
+                    name,
+                    algorithm.hex_length()
+                ),
+                position,
+            )
+        })
+}
+
+/// Parses `CONDITION:THEN:ELSE` part of a conditional section, located at `source[start..end]`
+fn parse_conditional(
+    source: &[u8],
+    start: usize,
+    end: usize,
+    captures_count: usize,
+    counters: &mut Vec<Counter>,
+) -> Result<Segment, TemplateError> {
+    let mut bounds = vec![start];
+    let mut rest = &source[start..end];
+    while let Some(index) = find_unescaped(rest, b':') {
+        bounds.push(bounds.last().unwrap() + index + 1);
+        rest = &rest[index + 1..];
+    }
+    bounds.push(end + 1);
+    if bounds.len() < 3 {
+        return Err(TemplateError::new(
+            "Conditional section must look like `#{?CONDITION:THEN}` or `#{?CONDITION:THEN:ELSE}`",
+            start,
+        ));
+    }
+    if bounds.len() > 4 {
+        return Err(TemplateError::new(
+            "Unexpected `:` in conditional section, write `\\:` for a literal colon",
+            bounds[3] - 1,
+        ));
+    }
+    let condition_text = text(source, start, bounds[1] - 1)?;
+    let negated = condition_text.starts_with('!');
+    let condition_text = condition_text.trim_start_matches('!');
+    let (index, regex) = match condition_text.split_once('~') {
+        Some((index, regex)) => (index, Some(regex)),
+        None => (condition_text, None),
+    };
+    let index = index
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|index| *index >= 1 && *index <= captures_count)
+        .ok_or_else(|| {
+            TemplateError::new(
+                format!(
+                    "Condition must start with a capture number from 1 to {}",
+                    captures_count
+                ),
+                start,
+            )
+        })?;
+    let regex = match regex {
+        Some(regex) => Some(Regex::new(&regex.replace("\\:", ":")).map_err(|err| {
+            TemplateError::new(format!("Invalid regex `{}`: {}", regex, err), start)
+        })?),
+        None => None,
+    };
+    let then = parse_segments(
+        source,
+        bounds[1],
+        bounds[2] - 1,
+        captures_count,
+        counters,
+        true,
+    )?;
+    let otherwise = match bounds.get(3) {
+        Some(&bound) => {
+            parse_segments(source, bounds[2], bound - 1, captures_count, counters, true)?
+        }
+        None => vec![],
+    };
+    Ok(Segment::Conditional {
+        condition: Condition {
+            index,
+            regex,
+            negated,
+        },
+        then,
+        otherwise,
+    })
+}
+
+/// `source[start..end]` as text. Only literal parts of a template may contain bytes, which are not valid UTF-8
+fn text(source: &[u8], start: usize, end: usize) -> Result<&str, TemplateError> {
+    std::str::from_utf8(&source[start..end]).map_err(|err| {
+        TemplateError::new(
+            "Bytes, which are not valid UTF-8, are only allowed in literal text, not inside `#{...}`",
+            start + err.valid_up_to(),
+        )
+    })
+}
+
+/// Parses counter options, e.g. `start=0,step=2`
+fn parse_counter(options: &str, position: usize) -> Result<Counter, TemplateError> {
+    let mut counter = Counter {
+        start: 1,
+        step: 1,
+        width: 0,
+        per_directory: false,
+        order: None,
+        size: 1,
+        position,
+    };
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        let (name, value) = option.split_once('=').ok_or_else(|| {
+            TemplateError::new(
+                format!("Counter option `{}` must look like `name=value`", option),
+                position,
+            )
+        })?;
+        let invalid = || {
+            TemplateError::new(
+                format!("Invalid value `{}` of counter option `{}`", value, name),
+                position,
+            )
+        };
+        match name.trim() {
+            "start" => counter.start = value.trim().parse().map_err(|_| invalid())?,
+            "step" => counter.step = value.trim().parse().map_err(|_| invalid())?,
+            "width" => counter.width = value.trim().parse().map_err(|_| invalid())?,
+            "size" => {
+                counter.size = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|size| *size > 0)
+                    .ok_or_else(invalid)?
+            }
+            "reset" => match value.trim() {
+                "dir" => counter.per_directory = true,
+                "none" => counter.per_directory = false,
+                _ => return Err(invalid()),
+            },
+            "sort" => {
+                counter.order = Some(
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|message| TemplateError::new(message, position))?,
+                )
+            }
+            _ => {
+                return Err(TemplateError::new(
+                    format!("Unknown counter option `{}`", name),
+                    position,
+                ))
+            }
+        }
+    }
+    Ok(counter)
+}
+
+fn parse_filter(filter: &str, position: usize) -> Result<Filter, TemplateError> {
+    let (name, argument) = match filter.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (filter, None),
+    };
+    match (name, argument) {
+        ("pad", argument) => argument
+            .unwrap_or("")
+            .parse::<usize>()
+            .map(Filter::Pad)
+            .map_err(|_| TemplateError::new("`pad` expects a width, e.g. `pad:3`", position)),
+        ("translit", None) => Ok(Filter::Transliterate),
+        ("slug", separator) => Ok(Filter::Slug(separator.unwrap_or("-").to_string())),
+        ("map", Some(argument)) => {
+            let (file, missing) = match argument.split_once(':') {
+                Some((file, missing)) => (file, missing.parse::<MissingKey>()),
+                None => (argument, Ok(MissingKey::Error)),
+            };
+            let missing = missing.map_err(|message| TemplateError::new(message, position))?;
+            let table = LookupTable::load(Path::new(file))
+                .map_err(|message| TemplateError::new(message, position))?;
+            Ok(Filter::Map {
+                table,
+                missing,
+                position,
+            })
+        }
+        ("date", argument) => DateReformat::parse(argument.unwrap_or(""))
+            .map(|reformat| Filter::Date { reformat, position })
+            .map_err(|message| TemplateError::new(message, position)),
+        ("map", None) => Err(TemplateError::new(
+            "`map` expects a lookup table file, e.g. `map:codes.csv`",
+            position,
+        )),
+        ("translit", Some(_)) => Err(TemplateError::new(
+            "`translit` does not take arguments",
+            position,
+        )),
+        _ => Err(TemplateError::new(
+            format!("Unknown template operator `{}`", name),
+            position,
+        )),
+    }
+}
+
+/// Recursive descent parser of integer expressions. Grammar is the usual one:
+/// `sum := product (('+' | '-') product)*`, `product := unary (('*' | '/' | '%') unary)*`, `unary := '-' unary | '(' sum ')' | number | '#' number`
+struct ExpressionParser<'a> {
+    source: &'a str,
+    offset: usize,
+    position: usize,
+    captures_count: usize,
+}
+
+impl<'a> ExpressionParser<'a> {
+    fn new(source: &'a str, offset: usize, captures_count: usize) -> Self {
+        ExpressionParser {
+            source,
+            offset,
+            position: 0,
+            captures_count,
+        }
+    }
+
+    fn parse(mut self) -> Result<Expression, TemplateError> {
+        let expression = self.parse_sum()?;
+        self.skip_whitespace();
+        if self.position < self.source.len() {
+            return Err(self.error("Unexpected symbol in expression"));
+        }
+        Ok(expression)
+    }
+
+    fn error(&self, message: &str) -> TemplateError {
+        TemplateError::new(message, self.offset + self.position)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.source[self.position..].starts_with(' ') {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.source[self.position..].chars().next()
+    }
+
+    fn parse_sum(&mut self) -> Result<Expression, TemplateError> {
+        let mut left = self.parse_product()?;
+        while let Some(operator @ ('+' | '-')) = self.peek() {
+            let position = self.offset + self.position;
+            self.position += 1;
+            let right = self.parse_product()?;
+            left = Expression::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+                position,
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_product(&mut self) -> Result<Expression, TemplateError> {
+        let mut left = self.parse_unary()?;
+        while let Some(operator @ ('*' | '/' | '%')) = self.peek() {
+            let position = self.offset + self.position;
+            self.position += 1;
+            let right = self.parse_unary()?;
+            left = Expression::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+                position,
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, TemplateError> {
+        match self.peek() {
+            Some('-') => {
+                let position = self.offset + self.position;
+                self.position += 1;
+                Ok(Expression::Negate {
+                    inner: Box::new(self.parse_unary()?),
+                    position,
+                })
+            }
+            Some('(') => {
+                self.position += 1;
+                let inner = self.parse_sum()?;
+                if self.peek() != Some(')') {
+                    return Err(self.error("Expected `)`"));
+                }
+                self.position += 1;
+                Ok(inner)
+            }
+            Some('#') => {
+                let position = self.offset + self.position;
+                self.position += 1;
+                let index = self.parse_number()?;
+                if index < 1 || index as usize > self.captures_count {
+                    return Err(TemplateError::new(
+                        format!(
+                            "Capture #{} does not exist, pattern has only {} stars",
+                            index, self.captures_count
+                        ),
+                        position,
+                    ));
+                }
+                Ok(Expression::Capture {
+                    index: index as usize,
+                    position,
+                })
+            }
+            Some(character) if character.is_ascii_digit() => {
+                Ok(Expression::Number(self.parse_number()?))
+            }
+            _ => Err(self.error("Expected a number, a capture or `(`")),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<i64, TemplateError> {
+        let digits: String = self.source[self.position..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        if digits.is_empty() {
+            return Err(self.error("Expected a number"));
+        }
+        let value = digits
+            .parse::<i64>()
+            .map_err(|_| self.error("Number is too large"))?;
+        self.position += digits.len();
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+fn test_context<'a>(path: &'a str, captures: Vec<&'a str>) -> FileContext<'a> {
+    FileContext::new(
+        Path::new(path),
+        captures.into_iter().map(str::as_bytes).collect(),
+    )
+}
+
+#[test]
+fn test_arithmetic() {
+    let template = Template::parse("ep#{1 + 12}_#{(#2 * 2) - 1|pad:3}".as_bytes(), 2).unwrap();
+    assert_eq!(
+        template.render(&test_context("", vec!["1", "05"])).unwrap(),
+        "ep13_009".as_bytes()
+    );
+    assert_eq!(
+        template
+            .render(&test_context("", vec!["-20", "0"]))
+            .unwrap(),
+        "ep-8_-01".as_bytes()
+    );
+}
+
+#[test]
+fn test_raw_bytes_literals() {
+    let template = Template::parse(b"\xcc\xe8\xf0_#1#{?1:_\xff}", 1).unwrap();
+    assert_eq!(
+        template.render(&test_context("", vec!["a"])).unwrap(),
+        b"\xcc\xe8\xf0_a_\xff"
+    );
+    let err = Template::parse(b"a#{1[0:\xff]}", 1).err().unwrap();
+    assert_eq!(err.position, 7);
+}
+
+#[test]
+fn test_arithmetic_errors() {
+    assert!(Template::parse("#{1 +}".as_bytes(), 1).is_err());
+    assert!(Template::parse("#{#3}".as_bytes(), 2).is_err());
+    assert!(Template::parse("#{1".as_bytes(), 1).is_err());
+    let template = Template::parse("#{#1 / #2}".as_bytes(), 2).unwrap();
+    assert_eq!(
+        template.render(&test_context("", vec!["7", "2"])).unwrap(),
+        "3".as_bytes()
+    );
+    assert!(template.render(&test_context("", vec!["7", "0"])).is_err());
+    assert!(template
+        .render(&test_context("", vec!["seven", "2"]))
+        .is_err());
+    let template = Template::parse("#{-#1}".as_bytes(), 1).unwrap();
+    assert_eq!(
+        template.render(&test_context("", vec!["-5"])).unwrap(),
+        "5".as_bytes()
+    );
+    assert!(template
+        .render(&test_context("", vec!["-9223372036854775808"]))
+        .is_err());
+    let err = Template::parse("#{1/2}".as_bytes(), 1).err().unwrap();
+    assert_eq!(err.position, 3);
+    let template = Template::parse("#{1/2/3}-#{#1/2}-#{1 / 2}".as_bytes(), 1).unwrap();
+    assert_eq!(
+        template.render(&test_context("", vec!["82"])).unwrap(),
+        "83-41-41".as_bytes()
+    );
+    let template = Template::parse("r#{1 / 4}".as_bytes(), 1).unwrap();
+    let err = template.render(&test_context("", vec!["x"])).unwrap_err();
+    assert_eq!(err.position, 3);
+}
+
+#[test]
+fn test_counter() {
+    let template = Template::parse(
+        "#{n:start=0,step=5,width=3,reset=dir,sort=natural}_#1".as_bytes(),
+        1,
+    )
+    .unwrap();
+    let counter = &template.counters()[0];
+    assert_eq!(counter.start, 0);
+    assert!(counter.per_directory);
+    let files = ["b/x10", "a/x2", "b/x9", "a/x1"].map(Path::new);
+    assert_eq!(counter.number(&files).unwrap(), vec![5, 5, 0, 0]);
+    let mut file = test_context("", vec!["x10"]);
+    file.counters = vec![10];
+    assert_eq!(template.render(&file).unwrap(), b"010_x10");
+    assert!(Template::parse("#{n:start=a}".as_bytes(), 0).is_err());
+    assert!(Template::parse("#{n:order=name}".as_bytes(), 0).is_err());
+
+    let template = Template::parse("#{bucket:2,width=2}/#{n:size=3}".as_bytes(), 0).unwrap();
+    let files = ["a", "b", "c", "d", "e"].map(Path::new);
+    assert_eq!(
+        template.counters()[0].number(&files).unwrap(),
+        vec![0, 0, 1, 1, 2]
+    );
+    assert_eq!(
+        template.counters()[1].number(&files).unwrap(),
+        vec![1, 1, 1, 2, 2]
+    );
+    let overflowing = Template::parse("#{n:step=9223372036854775807}".as_bytes(), 0).unwrap();
+    assert!(overflowing.counters()[0].number(&files).is_err());
+    let mut file = test_context("", vec![]);
+    file.counters = vec![1, 2];
+    assert_eq!(template.render(&file).unwrap(), b"01/2");
+    assert!(Template::parse("#{bucket}".as_bytes(), 0).is_err());
+    assert!(Template::parse("#{bucket:0}".as_bytes(), 0).is_err());
+    assert!(Template::parse("#{n:size=x}".as_bytes(), 0).is_err());
+}
+
+#[test]
+fn test_hash_tokens() {
+    let template = Template::parse("#{sha256:12}_#{md5:4}_#{sha256}".as_bytes(), 0).unwrap();
+    assert_eq!(
+        template.hash_algorithms(),
+        vec![HashAlgorithm::Sha256, HashAlgorithm::Md5]
+    );
+    let mut file = test_context("abc", vec![]);
+    file.hashes.insert(HashAlgorithm::Sha256, "ab".repeat(32));
+    file.hashes.insert(HashAlgorithm::Md5, "cd".repeat(16));
+    assert_eq!(
+        template.render(&file).unwrap(),
+        format!("abababababab_cdcd_{}", "ab".repeat(32)).into_bytes()
+    );
+    assert!(Template::parse("#{md5:33}".as_bytes(), 0).is_err());
+
+    let template = Template::parse("#{shard}/#{shard:3}/#0".as_bytes(), 0).unwrap();
+    assert_eq!(template.hash_algorithms(), vec![HashAlgorithm::Sha256]);
+    let mut file = test_context("x", vec![]);
+    file.hashes.insert(HashAlgorithm::Sha256, "f00d".repeat(16));
+    assert_eq!(template.render(&file).unwrap(), b"f0/f00/x");
+    assert!(Template::parse("#{shard:0}".as_bytes(), 0).is_err());
+    assert!(Template::parse("#{n}".as_bytes(), 0)
+        .unwrap()
+        .hash_algorithms()
+        .is_empty());
+}
+
+#[test]
+fn test_path_tokens() {
+    let template = Template::parse(
+        "#{dir}/#{parent:2}_#{parent}_#{stem}.#{ext}|#{name}|#0|#1".as_bytes(),
+        1,
+    )
+    .unwrap();
+    let file = test_context("photos/2023/beach.tar.gz", vec!["x"]);
+    assert_eq!(
+        template.render(&file).unwrap(),
+        "photos/2023/photos_2023_beach.tar.gz|beach.tar.gz|photos/2023/beach.tar.gz|x".as_bytes()
+    );
+    let template = Template::parse("#{dir}/#{stem}#{ext}".as_bytes(), 0).unwrap();
+    let file = test_context(".bashrc", vec![]);
+    assert_eq!(template.render(&file).unwrap(), b"./.bashrc");
+    assert!(Template::parse("#{parent}".as_bytes(), 0)
+        .unwrap()
+        .render(&file)
+        .is_err());
+    assert!(Template::parse("#{parent:0}".as_bytes(), 0).is_err());
+}
+
+#[test]
+fn test_capture_operations() {
+    let template = Template::parse(
+        "#{1[0:4]}-#{1[-2:]}-#{2:-unknown}-#{1/ /_}-#{1}".as_bytes(),
+        2,
+    )
+    .unwrap();
+    assert_eq!(
+        template
+            .render(&test_context("", vec!["2026 report v1", ""]))
+            .unwrap(),
+        "2026-v1-unknown-2026_report_v1-2026 report v1".as_bytes()
+    );
+    let template =
+        Template::parse("#{1/(\\d+)-(\\d+)/$2.$1:-none}|#{1/\\//_}".as_bytes(), 1).unwrap();
+    assert_eq!(
+        template.render(&test_context("", vec!["10-18"])).unwrap(),
+        "18.10|10-18".as_bytes()
+    );
+    assert_eq!(
+        template.render(&test_context("", vec![""])).unwrap(),
+        "none|".as_bytes()
+    );
+    let template = Template::parse("#{1/-\\|\\s/_}|#{1/[\\|]/+}".as_bytes(), 1).unwrap();
+    assert_eq!(
+        template.render(&test_context("", vec!["a-b|c d"])).unwrap(),
+        "a_b|c_d|a-b+c d".as_bytes()
+    );
+    let template = Template::parse("#{1:-a\\|b}".as_bytes(), 1).unwrap();
+    assert_eq!(
+        template.render(&test_context("", vec![""])).unwrap(),
+        "a|b".as_bytes()
+    );
+    assert!(Template::parse("#{1/(/_}".as_bytes(), 1).is_err());
+    assert!(Template::parse("#{1[a:b]}".as_bytes(), 1).is_err());
+    assert!(Template::parse("#{3:-x}".as_bytes(), 2).is_err());
+}
+
+#[test]
+fn test_conditional() {
+    let template = Template::parse(
+        "#1#{?2:_v#{2|pad:2}}#{?!3:_draft:_final\\:#3}#{?1~^\\d+$:_num}".as_bytes(),
+        3,
+    )
+    .unwrap();
+    assert_eq!(
+        template
+            .render(&test_context("", vec!["report", "3", ""]))
+            .unwrap(),
+        "report_v03_draft".as_bytes()
+    );
+    assert_eq!(
+        template
+            .render(&test_context("", vec!["42", "", "ok"]))
+            .unwrap(),
+        "42_final:ok_num".as_bytes()
+    );
+    let error = Template::parse("#1#{?1:a:b:c}".as_bytes(), 1)
+        .err()
+        .unwrap();
+    assert_eq!(error.position, 10);
+    assert_eq!(
+        error.pointing_at(b"#1#{?1:a:b:c}"),
+        "#1#{?1:a:b:c}\n          ^ Unexpected `:` in conditional section, write `\\:` for a literal colon"
+    );
+    assert!(Template::parse("#{?4:x}".as_bytes(), 3).is_err());
+    assert!(Template::parse("#{?1}".as_bytes(), 1).is_err());
+}
+
+#[test]
+fn test_slug_filters() {
+    let template = Template::parse("#{1|translit}/#{2|slug}_#{2|slug:_}".as_bytes(), 2).unwrap();
+    assert_eq!(
+        template
+            .render(&test_context("", vec!["Ёлка", "Новый год, 2026!"]))
+            .unwrap(),
+        "Iolka/novyi-god-2026_novyi_god_2026".as_bytes()
+    );
+    assert!(Template::parse("#{1|translit:ru}".as_bytes(), 1).is_err());
+}
+
+#[test]
+fn test_raw_bytes() {
+    let template = Template::parse("#{dir}/new_#1.#{ext}|#{1|slug}".as_bytes(), 1).unwrap();
+    // "Мир" in CP1251, which is not valid UTF-8
+    let path = Path::new(std::ffi::OsStr::from_bytes(b"old/\xcc\xe8\xf0.txt"));
+    let file = FileContext::new(path, vec![b"\xcc\xe8\xf0"]);
+    assert_eq!(
+        template.render(&file).unwrap(),
+        b"old/new_\xcc\xe8\xf0.txt|"
+    );
+}
+
+#[test]
+fn test_date_filter() {
+    let template = Template::parse(
+        "#{1|date:%d.%m.%Y=>%Y-%m-%d}_#{2|date:%b %d %Y=>%Y%m%d}".as_bytes(),
+        2,
+    )
+    .unwrap();
+    assert_eq!(
+        template
+            .render(&test_context("", vec!["18.10.2026", "Oct 9 2026"]))
+            .unwrap(),
+        b"2026-10-18_20261009"
+    );
+    let error = template
+        .render(&test_context("", vec!["2026-10-18", "Oct 9 2026"]))
+        .err()
+        .unwrap();
+    assert_eq!(error.position, 4);
+    assert!(Template::parse("#{1|date:%Y}".as_bytes(), 1).is_err());
+}
@@ -6,7 +6,7 @@ use serial_test::serial;
 use std::{
     env::set_current_dir,
     fs::{self, create_dir_all, File},
-    path::{Path, PathBuf},
+    path::Path,
 };
 
 struct TestParams<'a> {
@@ -35,30 +35,29 @@ impl TestParams<'_> {
 }
 
 fn prepare_location(filenames: &Vec<&str>, directories: &Vec<&str>) -> Option<Command> {
-    let path = "target/debug";
-    set_current_dir(path);
-    let cmd = Command::new(PathBuf::from("mmv"));
-    fs::remove_dir_all("tmp");
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/debug");
+    let cmd = Command::cargo_bin("mmv").unwrap();
+    set_current_dir(&path).unwrap();
+    let _ = fs::remove_dir_all("tmp");
     dbg!(Path::new("./tmp/").exists());
     dbg!(fs::create_dir("./tmp/").is_ok());
     if Path::new("./tmp/").exists() || fs::create_dir("./tmp/").is_ok() {
         for file in filenames {
             let file_full_path = Path::new("./tmp/").join(file);
-            let file_dir = file_full_path.parent();
-            if file_dir.is_some() {
-                create_dir_all(file_dir.unwrap());
+            if let Some(file_dir) = file_full_path.parent() {
+                create_dir_all(file_dir).unwrap();
             }
             let created_file = File::create(Path::new("./tmp/").join(file));
             if created_file.is_err() {
                 dbg!(created_file.err());
                 return None;
             }
-            created_file.unwrap().sync_all();
+            created_file.unwrap().sync_all().unwrap();
         }
         for directory in directories {
-            create_dir_all(Path::new("./tmp/").join(directory));
+            create_dir_all(Path::new("./tmp/").join(directory)).unwrap();
         }
-        set_current_dir("tmp/");
+        set_current_dir("tmp/").unwrap();
         Some(cmd)
     } else {
         dbg!(path);
@@ -73,11 +72,36 @@ fn check_moves(changed_names: &Vec<&str>) {
 }
 
 fn leave_location() {
-    set_current_dir("..");
-    fs::remove_dir_all("tmp");
+    set_current_dir("..").unwrap();
+    fs::remove_dir_all("tmp").unwrap();
 }
 
 fn run_with_params(params: TestParams) {
+    run_with_args(params, &[])
+}
+
+fn run_with_args(params: TestParams, args: &[&str]) {
+    let TestParams {
+        filenames,
+        directories,
+        pattern,
+        target,
+        new_names,
+    } = params;
+    let command = prepare_location(&filenames, &directories);
+    if command.is_none() {
+        panic!("Initialization failed, please retry testing")
+    }
+    command
+        .unwrap()
+        .args(args)
+        .arg(pattern)
+        .arg(target)
+        .unwrap();
+    check_moves(&new_names);
+    leave_location()
+}
+fn run_failing_with_params(params: TestParams) {
     let TestParams {
         filenames,
         directories,
@@ -89,10 +113,11 @@ fn run_with_params(params: TestParams) {
     if command.is_none() {
         panic!("Initialization failed, please retry testing")
     }
-    command.unwrap().arg(pattern).arg(target).unwrap();
+    command.unwrap().arg(pattern).arg(target).assert().failure();
     check_moves(&new_names);
     leave_location()
 }
+
 #[test]
 #[serial]
 fn test_single_file() {
@@ -160,3 +185,856 @@ fn test_cut_suffix() {
     );
     run_with_params(params)
 }
+
+#[test]
+#[serial]
+fn test_arithmetic() {
+    let params = TestParams::new(
+        vec!["ep1.mkv", "ep2.mkv", "ep10.mkv"],
+        vec![],
+        "ep*.mkv",
+        "ep#{1 + 12|pad:3}.mkv",
+        vec!["ep013.mkv", "ep014.mkv", "ep022.mkv"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_arithmetic_fails_before_moving() {
+    let params = TestParams::new(
+        vec!["ep1.mkv", "ep2.mkv", "epX.mkv"],
+        vec![],
+        "ep*.mkv",
+        "ep#{1 + 12}.mkv",
+        vec!["ep1.mkv", "ep2.mkv", "epX.mkv"],
+    );
+    run_failing_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_empty_name_fails_before_moving() {
+    let params = TestParams::new(vec!["a_x", "b_"], vec![], "*_*", "#2", vec!["a_x", "b_"]);
+    run_failing_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_counter() {
+    let params = TestParams::new(
+        vec!["a/img9.jpg", "a/img10.jpg", "b/img1.jpg"],
+        vec![],
+        "*/img*.jpg",
+        "#1/#{n:width=3,reset=dir,sort=natural}.jpg",
+        vec!["a/001.jpg", "a/002.jpg", "b/001.jpg"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_sort_reverse() {
+    let params = TestParams::new(
+        vec!["f1", "f2", "f10"],
+        vec![],
+        "f*",
+        "g#{n}_#1",
+        vec!["g1_10", "g2_2", "g3_1"],
+    );
+    run_with_args(params, &["--sort", "natural", "--reverse"])
+}
+
+#[test]
+#[serial]
+fn test_metadata_tokens() {
+    let params = TestParams::new(
+        vec!["report.txt"],
+        vec![],
+        "*.txt",
+        "#1_#{size}_#{size:human}.txt",
+        vec!["report_0_0.txt"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_hash_tokens() {
+    let params = TestParams::new(
+        vec!["assets/logo.png"],
+        vec![],
+        "assets/*.png",
+        "assets/#{sha256:8}_#{md5:6}.png",
+        vec!["assets/e3b0c442_d41d8c.png"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_sharding_tokens() {
+    let params = TestParams::new(
+        vec!["f1", "f2", "f3"],
+        vec!["0", "1"],
+        "f*",
+        "#{bucket:2}/#{shard}_#1",
+        vec!["0/e3_1", "0/e3_2", "1/e3_3"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_path_tokens() {
+    let params = TestParams::new(
+        vec!["photos/2023/beach.jpeg", "photos/2024/city.jpeg"],
+        vec![],
+        "photos/*.jpeg",
+        "#{dir}/#{parent}_#{stem}.jpg",
+        vec!["photos/2023/2023_beach.jpg", "photos/2024/2024_city.jpg"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_capture_operations() {
+    let params = TestParams::new(
+        vec!["20261018 annual report_.txt", "20261019 notes_v2.txt"],
+        vec!["2026"],
+        "* *_*.txt",
+        "#{1[0:4]}/#{2/ /-}_#{3:-v1}.txt",
+        vec!["2026/annual-report_v1.txt", "2026/notes_v2.txt"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_conditional() {
+    let params = TestParams::new(
+        vec!["report-2.txt", "summary-.txt"],
+        vec![],
+        "*-*.txt",
+        "#1#{?2:_v#2:_draft}.txt",
+        vec!["report_v2.txt", "summary_draft.txt"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_slug() {
+    let params = TestParams::new(
+        vec!["uploads/Отчёт за 2026.pdf", "uploads/Алёнка.pdf"],
+        vec![],
+        "uploads/*.pdf",
+        "uploads/#{1|slug}.pdf",
+        vec!["uploads/otchiot-za-2026.pdf", "uploads/alionka.pdf"],
+    );
+    run_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_sanitize() {
+    let params = TestParams::new(
+        vec!["notes_a.txt", "notes_b.txt"],
+        vec![],
+        "notes_*.txt",
+        "#{?1~a:con:what?}.#1.",
+        vec!["con_.a", "what_.b"],
+    );
+    run_with_args(params, &["--sanitize"])
+}
+
+#[test]
+#[serial]
+fn test_dry_run() {
+    let params = TestParams::new(vec!["a1", "a2"], vec![], "a*", "b#1", vec!["a1", "a2"]);
+    run_with_args(params, &["--dry-run"])
+}
+
+#[test]
+#[serial]
+fn test_collision_fails_before_moving() {
+    let params = TestParams::new(
+        vec!["a1", "a2", "c"],
+        vec![],
+        "*",
+        "#{?1~^a:b:d}",
+        vec!["a1", "a2", "c"],
+    );
+    run_failing_with_params(params)
+}
+
+#[test]
+#[serial]
+fn test_from_encoding() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    let command = prepare_location(&vec!["ascii.txt", "Привет.txt"], &vec![]);
+    // "Мир.txt" in CP1251
+    File::create(OsStr::from_bytes(b"\xcc\xe8\xf0.txt")).unwrap();
+    command
+        .unwrap()
+        .args(["--from-encoding", "cp1251", "*.txt"])
+        .unwrap();
+    check_moves(&vec!["Мир.txt", "Привет.txt", "ascii.txt"]);
+    assert!(!Path::new(OsStr::from_bytes(b"\xcc\xe8\xf0.txt")).exists());
+    leave_location();
+
+    // Names outside of the pattern can not fail the run, even if they can not be converted
+    let command = prepare_location(&vec!["docs/Мир.txt", "other/日本.txt"], &vec![]);
+    command
+        .unwrap()
+        .args(["--to-encoding", "koi8-r", "docs/*"])
+        .unwrap();
+    // "Мир.txt" in KOI8-R
+    assert!(Path::new(OsStr::from_bytes(b"docs/\xed\xc9\xd2.txt")).exists());
+    assert!(Path::new("other/日本.txt").exists());
+    leave_location();
+
+    let command = prepare_location(&vec![], &vec!["docs", "other"]);
+    File::create(OsStr::from_bytes(b"docs/\xcc\xe8\xf0.txt")).unwrap();
+    // 0x98 is not a CP1251 character
+    File::create(OsStr::from_bytes(b"other/\x98.txt")).unwrap();
+    command
+        .unwrap()
+        .args(["--from-encoding", "cp1251", "docs/*"])
+        .unwrap();
+    assert!(Path::new("docs/Мир.txt").exists());
+    leave_location();
+
+    // Directories on the path of a matched file are converted too
+    let command = prepare_location(&vec![], &vec![]);
+    create_dir_all(OsStr::from_bytes(b"\xcc\xe8\xf0/\xe0")).unwrap();
+    File::create(OsStr::from_bytes(b"\xcc\xe8\xf0/\xe0/\xcc\xe8\xf0.txt")).unwrap();
+    File::create(OsStr::from_bytes(b"\xcc\xe8\xf0/\xe0.txt")).unwrap();
+    command
+        .unwrap()
+        .args(["--from-encoding", "cp1251", "*.txt"])
+        .unwrap();
+    check_moves(&vec!["Мир/а/Мир.txt", "Мир/а.txt"]);
+    assert!(!Path::new(OsStr::from_bytes(b"\xcc\xe8\xf0")).exists());
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_normalize() {
+    let command = prepare_location(&vec!["cafe\u{301}.txt", "ascii.txt"], &vec![]);
+    command
+        .unwrap()
+        .args(["--normalize", "nfc", "caf\u{e9}*"])
+        .unwrap();
+    check_moves(&vec!["ascii.txt", "caf\u{e9}.txt"]);
+    assert!(!Path::new("cafe\u{301}.txt").exists());
+    leave_location();
+
+    let command = prepare_location(&vec!["cafe\u{301}.txt", "caf\u{e9}.txt"], &vec![]);
+    command
+        .unwrap()
+        .args(["--normalize", "nfc", "*"])
+        .assert()
+        .failure();
+    assert!(Path::new("cafe\u{301}.txt").exists());
+    assert!(Path::new("caf\u{e9}.txt").exists());
+    leave_location();
+
+    // Directories are normalized along with files, so the tree does not mix both forms
+    let command = prepare_location(&vec!["cafe\u{301}/cafe\u{301}.txt"], &vec![]);
+    command
+        .unwrap()
+        .args(["--normalize", "nfc", "*.txt"])
+        .unwrap();
+    check_moves(&vec!["caf\u{e9}/caf\u{e9}.txt"]);
+    assert!(!Path::new("cafe\u{301}").exists());
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_raw_bytes_names() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    let command = prepare_location(&vec!["ascii.txt"], &vec!["old", "new"]);
+    // "Мир" in CP1251, which is not valid UTF-8
+    File::create(OsStr::from_bytes(b"old/\xcc\xe8\xf0.txt")).unwrap();
+    File::create(OsStr::from_bytes(b"\xff.log")).unwrap();
+    command
+        .unwrap()
+        .args(["old/*.txt", "new/#{name}_#1.bak"])
+        .unwrap();
+    assert!(Path::new(OsStr::from_bytes(b"new/\xcc\xe8\xf0.txt_\xcc\xe8\xf0.bak")).exists());
+    assert!(!Path::new(OsStr::from_bytes(b"old/\xcc\xe8\xf0.txt")).exists());
+    assert!(Path::new(OsStr::from_bytes(b"\xff.log")).exists());
+    leave_location();
+
+    // Templates may contain such bytes too
+    let command = prepare_location(&vec!["a.txt"], &vec![]);
+    command
+        .unwrap()
+        .arg("*.txt")
+        .arg(OsStr::from_bytes(b"\xcc\xe8\xf0_#1.txt"))
+        .unwrap();
+    assert!(Path::new(OsStr::from_bytes(b"\xcc\xe8\xf0_a.txt")).exists());
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_lookup_table() {
+    let command = prepare_location(&vec!["DEP042.pdf", "DEP007.pdf", "DEP999.pdf"], &vec![]);
+    fs::write("codes.csv", "DEP042,Accounting\n\"DEP007\",\"Sales, EU\"\n").unwrap();
+    command
+        .unwrap()
+        .args(["*.pdf", "#{1|map:codes.csv}.pdf"])
+        .assert()
+        .failure();
+    assert!(Path::new("DEP042.pdf").exists());
+    leave_location();
+
+    let command = prepare_location(&vec!["DEP042.pdf", "DEP007.pdf", "DEP999.pdf"], &vec![]);
+    fs::write("codes.csv", "DEP042,Accounting\n\"DEP007\",\"Sales, EU\"\n").unwrap();
+    command
+        .unwrap()
+        .args(["*.pdf", "#{1|map:codes.csv:-Other|slug}.pdf"])
+        .unwrap();
+    check_moves(&vec![
+        "accounting.pdf",
+        "codes.csv",
+        "other.pdf",
+        "sales-eu.pdf",
+    ]);
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_date_reformat() {
+    let command = prepare_location(&vec!["scan_18.10.2026.pdf", "scan_2.01.2025.pdf"], &vec![]);
+    command
+        .unwrap()
+        .args(["scan_*.pdf", "#{1|date:%d.%m.%Y=>%Y-%m-%d}.pdf"])
+        .unwrap();
+    check_moves(&vec!["2026-10-18.pdf", "2025-01-02.pdf"]);
+    leave_location();
+
+    let command = prepare_location(&vec!["scan_18.10.2026.pdf", "scan_draft.pdf"], &vec![]);
+    command
+        .unwrap()
+        .args(["scan_*.pdf", "#{1|date:%d.%m.%Y=>%Y-%m-%d}.pdf"])
+        .assert()
+        .failure();
+    check_moves(&vec!["scan_18.10.2026.pdf", "scan_draft.pdf"]);
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_copy() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+    let command = prepare_location(&vec![], &vec![]);
+    fs::write("app.conf.template", "port = 80\n").unwrap();
+    fs::set_permissions("app.conf.template", fs::Permissions::from_mode(0o640)).unwrap();
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    File::options()
+        .write(true)
+        .open("app.conf.template")
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    let with_xattr = unsafe {
+        libc::setxattr(
+            c"app.conf.template".as_ptr(),
+            c"user.origin".as_ptr(),
+            b"ci".as_ptr().cast(),
+            2,
+            0,
+        ) == 0
+    };
+    command
+        .unwrap()
+        .args(["--copy", "*.conf.template", "#1.conf"])
+        .unwrap();
+    assert!(Path::new("app.conf.template").exists());
+    assert_eq!(fs::read_to_string("app.conf").unwrap(), "port = 80\n");
+    let metadata = fs::metadata("app.conf").unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+    assert_eq!(metadata.modified().unwrap(), modified);
+    if with_xattr {
+        let mut value = [0u8; 16];
+        let size = unsafe {
+            libc::getxattr(
+                c"app.conf".as_ptr(),
+                c"user.origin".as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        assert_eq!(&value[..size.max(0) as usize], b"ci");
+    }
+
+    // `mcp` is `mmv` in copy mode, and it does not overwrite files without `-f`
+    let mcp = Path::new("..").join("mcp");
+    if !mcp.exists() {
+        std::os::unix::fs::symlink(assert_cmd::cargo::cargo_bin("mmv"), &mcp).unwrap();
+    }
+    fs::write("app.conf", "changed\n").unwrap();
+    Command::new(&mcp)
+        .args(["*.conf.template", "#1.conf"])
+        .output()
+        .unwrap();
+    assert_eq!(fs::read_to_string("app.conf").unwrap(), "changed\n");
+    Command::new(&mcp)
+        .args(["-f", "*.conf.template", "#1.conf"])
+        .unwrap();
+    assert_eq!(fs::read_to_string("app.conf").unwrap(), "port = 80\n");
+    assert!(Path::new("app.conf.template").exists());
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_links() {
+    use std::os::unix::fs::MetadataExt;
+    let command = prepare_location(&vec!["raw/a.csv", "raw/b.csv"], &vec!["hard", "views/all"]);
+    command
+        .unwrap()
+        .args(["--link", "raw/*.csv", "hard/#1.csv"])
+        .unwrap();
+    assert_eq!(
+        fs::metadata("raw/a.csv").unwrap().ino(),
+        fs::metadata("hard/a.csv").unwrap().ino()
+    );
+    leave_location();
+
+    let command = prepare_location(&vec!["raw/a.csv", "raw/b.csv"], &vec!["views/all"]);
+    command
+        .unwrap()
+        .args(["--symlink", "raw/*.csv", "views/all/#1.csv"])
+        .unwrap();
+    assert_eq!(
+        fs::read_link("views/all/a.csv").unwrap(),
+        Path::new("../../raw/a.csv")
+    );
+    assert!(Path::new("raw/a.csv").exists());
+    assert!(fs::metadata("views/all/b.csv").is_ok());
+    leave_location();
+
+    let command = prepare_location(&vec!["raw/a.csv", "views/a.csv"], &vec![]);
+    command
+        .unwrap()
+        .args(["--symlink=absolute", "raw/*.csv", "views/#1.csv"])
+        .output()
+        .unwrap();
+    assert!(fs::symlink_metadata("views/a.csv").unwrap().is_file());
+    Command::cargo_bin("mmv")
+        .unwrap()
+        .args(["-f", "--symlink=absolute", "raw/*.csv", "views/#1.csv"])
+        .unwrap();
+    let target = fs::read_link("views/a.csv").unwrap();
+    assert!(target.is_absolute());
+    assert!(target.ends_with("tmp/raw/a.csv"));
+
+    // Copying over a link to the source must not truncate the source
+    fs::write("raw/a.csv", "id\n1\n").unwrap();
+    Command::cargo_bin("mmv")
+        .unwrap()
+        .args(["-f", "--copy", "raw/*.csv", "views/#1.csv"])
+        .output()
+        .unwrap();
+    assert_eq!(fs::read_to_string("raw/a.csv").unwrap(), "id\n1\n");
+    fs::hard_link("raw/a.csv", "raw/b.csv").unwrap();
+    Command::cargo_bin("mmv")
+        .unwrap()
+        .args(["-f", "--copy", "raw/a.csv", "raw/b.csv"])
+        .output()
+        .unwrap();
+    assert_eq!(fs::read_to_string("raw/a.csv").unwrap(), "id\n1\n");
+
+    // A link to another file is replaced with the copy, not written through
+    fs::write("other.csv", "other\n").unwrap();
+    fs::remove_file("views/a.csv").unwrap();
+    std::os::unix::fs::symlink("../other.csv", "views/a.csv").unwrap();
+    Command::cargo_bin("mmv")
+        .unwrap()
+        .args(["-f", "--copy", "raw/a.csv", "views/a.csv"])
+        .unwrap();
+    assert!(fs::symlink_metadata("views/a.csv").unwrap().is_file());
+    assert_eq!(fs::read_to_string("views/a.csv").unwrap(), "id\n1\n");
+    assert_eq!(fs::read_to_string("other.csv").unwrap(), "other\n");
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_append() {
+    let command = prepare_location(&vec![], &vec!["logs"]);
+    for (name, contents) in [
+        ("logs/log_18_part1.txt", "a1\n"),
+        ("logs/log_18_part2.txt", "a2\n"),
+        ("logs/log_18_part10.txt", "a10\n"),
+        ("logs/log_19_part1.txt", "b1\n"),
+        ("log_19.txt", "b0\n"),
+    ] {
+        fs::write(name, contents).unwrap();
+    }
+    command
+        .unwrap()
+        .args([
+            "--append",
+            "--sort",
+            "natural",
+            "logs/log_*_part*.txt",
+            "log_#1.txt",
+        ])
+        .unwrap();
+    assert_eq!(fs::read_to_string("log_18.txt").unwrap(), "a1\na2\na10\n");
+    assert_eq!(fs::read_to_string("log_19.txt").unwrap(), "b0\nb1\n");
+    assert!(Path::new("logs/log_18_part10.txt").exists());
+
+    Command::cargo_bin("mmv")
+        .unwrap()
+        .args(["--append", "log_*.txt", "log_18.txt"])
+        .assert()
+        .failure();
+    assert_eq!(fs::read_to_string("log_19.txt").unwrap(), "b0\nb1\n");
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_move_across_filesystems() {
+    // /dev/shm is usually a tmpfs, so moving there from the build directory crosses filesystems
+    let other = Path::new("/dev/shm").join(format!("mmv-test-{}", std::process::id()));
+    if fs::create_dir(&other).is_err() {
+        return;
+    }
+    let command = prepare_location(&vec![], &vec![]);
+    fs::write("report.txt", "quarterly\n").unwrap();
+    command
+        .unwrap()
+        .args(["report.*", &format!("{}/moved.#1", other.display())])
+        .unwrap();
+    assert!(!Path::new("report.txt").exists());
+    assert_eq!(
+        fs::read_to_string(other.join("moved.txt")).unwrap(),
+        "quarterly\n"
+    );
+    assert!(!other.join(".moved.txt.mmv-partial").exists());
+    fs::remove_dir_all(&other).unwrap();
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_create_dirs() {
+    use std::os::unix::fs::PermissionsExt;
+    let command = prepare_location(&vec!["img_2026_a.jpg", "img_2025_b.jpg"], &vec![]);
+    let output = command
+        .unwrap()
+        .args(["-n", "-p", "img_*_*.jpg", "photos/#1/#2.jpg"])
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Would create directory: photos/2025"));
+    assert!(!Path::new("photos").exists());
+    Command::cargo_bin("mmv")
+        .unwrap()
+        .args(["-p", "--dir-mode", "750", "img_*_*.jpg", "photos/#1/#2.jpg"])
+        .unwrap();
+    check_moves(&vec!["photos/2026/a.jpg", "photos/2025/b.jpg"]);
+    assert_eq!(
+        fs::metadata("photos/2026").unwrap().permissions().mode() & 0o777,
+        0o750
+    );
+    leave_location();
+
+    // Directories created for a failed run are removed again
+    let command = prepare_location(&vec!["a.txt", "b.txt", "existing/a.dat"], &vec![]);
+    command
+        .unwrap()
+        .args(["-p", "*.txt", "#{?1~^a$:existing:new/deep}/#1.dat"])
+        .output()
+        .unwrap();
+    assert!(!Path::new("new").exists());
+    check_moves(&vec!["a.txt", "b.txt"]);
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_prune_empty() {
+    let command = prepare_location(
+        &vec![
+            "2026/03/x.jpg",
+            "2026/03/y.jpg",
+            "2025/01/z.jpg",
+            "2025/notes.txt",
+        ],
+        &vec!["2026/empty"],
+    );
+    command
+        .unwrap()
+        .args(["--prune-empty", "*/*/*.jpg", "#1/#3.jpg"])
+        .unwrap();
+    check_moves(&vec![
+        "2026/x.jpg",
+        "2026/y.jpg",
+        "2025/z.jpg",
+        "2026/empty",
+    ]);
+    assert!(!Path::new("2026/03").exists());
+    assert!(!Path::new("2025/01").exists());
+    leave_location();
+
+    let command = prepare_location(&vec!["old/nested/file.txt"], &vec![]);
+    let output = command
+        .unwrap()
+        .args(["-n", "--prune-empty", "old/nested/*", "#1"])
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Would remove empty directory: old/nested"));
+    assert!(stdout.contains("Would remove empty directory: old\n"));
+    Command::cargo_bin("mmv")
+        .unwrap()
+        .args(["--prune-empty", "old/nested/*", "#1"])
+        .unwrap();
+    check_moves(&vec!["file.txt"]);
+    assert!(!Path::new("old").exists());
+    assert!(Path::new("../tmp").exists());
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_directories() {
+    let command = prepare_location(
+        &vec![
+            "photos_2025/a.jpg",
+            "photos_2025/sub/b.jpg",
+            "photos_2026/c.jpg",
+            "photos_notes.txt",
+        ],
+        &vec!["archive"],
+    );
+    command
+        .unwrap()
+        .args(["-d", "photos_*", "archive/#1"])
+        .unwrap();
+    check_moves(&vec![
+        "archive/2025/a.jpg",
+        "archive/2025/sub/b.jpg",
+        "archive/2026/c.jpg",
+        "archive/notes.txt",
+    ]);
+    assert!(!Path::new("photos_2025").exists());
+    leave_location();
+
+    // Moving a directory into another moved directory depends on the order of moves, so it is refused
+    let command = prepare_location(&vec!["x/1", "y/2"], &vec![]);
+    command
+        .unwrap()
+        .args(["-d", "*", "#{?1~^x$:y/x:z}"])
+        .assert()
+        .failure();
+    check_moves(&vec!["x/1", "y/2"]);
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_flatten() {
+    let command = prepare_location(
+        &vec![
+            "a/b/x.jpg",
+            "a_b/x.jpg",
+            "flat/a_b_x_1.jpg",
+            "flat/old/y.jpg",
+            "c.txt",
+        ],
+        &vec![],
+    );
+    command
+        .unwrap()
+        .args(["--flatten", "flat", "*.jpg"])
+        .unwrap();
+    check_moves(&vec![
+        "flat/a_b_x.jpg",
+        "flat/a_b_x_2.jpg",
+        "flat/a_b_x_1.jpg",
+        "flat/old_y.jpg",
+        "c.txt",
+    ]);
+    leave_location();
+
+    let command = prepare_location(&vec!["a/b/x.jpg"], &vec![]);
+    command
+        .unwrap()
+        .args(["-p", "--flatten", "flat", "--separator", "-", "*/*/*"])
+        .unwrap();
+    check_moves(&vec!["flat/a-b-x.jpg"]);
+    leave_location();
+
+    // `--sanitize` and `--on-conflict` apply to flattened names too
+    let command = prepare_location(&vec!["a?/x.jpg", "a_/x.jpg"], &vec!["flat"]);
+    command
+        .unwrap()
+        .args([
+            "--flatten",
+            "flat",
+            "--sanitize",
+            "--on-conflict",
+            "rename-paren",
+            "*.jpg",
+        ])
+        .unwrap();
+    check_moves(&vec!["flat/a__x.jpg", "flat/a__x (1).jpg"]);
+    leave_location();
+
+    let command = prepare_location(&vec!["a/b/x.jpg", "a_b/x.jpg"], &vec![]);
+    command
+        .unwrap()
+        .args(["-p", "--flatten", "flat", "--on-conflict", "skip", "*.jpg"])
+        .unwrap();
+    check_moves(&vec!["flat/a_b_x.jpg", "a_b/x.jpg"]);
+    assert!(!Path::new("flat/a_b_x_1.jpg").exists());
+    leave_location();
+
+    let command = prepare_location(&vec!["a/x"], &vec![]);
+    command
+        .unwrap()
+        .args(["--flatten", ".", "*", "#1"])
+        .assert()
+        .failure();
+    check_moves(&vec!["a/x"]);
+    leave_location()
+}
+
+#[test]
+#[serial]
+fn test_on_conflict() {
+    use std::time::{Duration, SystemTime};
+    // Both sources are mapped to `out/x.txt`, which exists already
+    let run = |strategy: &str, input: &str| {
+        let command = prepare_location(&vec![], &vec!["src1", "src2", "out"]);
+        fs::write("src1/x.txt", "1").unwrap();
+        fs::write("src2/x.txt", "22").unwrap();
+        fs::write("out/x.txt", "").unwrap();
+        let newest = SystemTime::now() + Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open("src1/x.txt")
+            .unwrap()
+            .set_modified(newest)
+            .unwrap();
+        command
+            .unwrap()
+            .args(["--on-conflict", strategy, "src*/x.txt", "out/x.txt"])
+            .write_stdin(input)
+            .unwrap();
+    };
+    let read = |path: &str| fs::read_to_string(path).unwrap();
+
+    run("skip", "");
+    assert_eq!(read("out/x.txt"), "");
+    check_moves(&vec!["src1/x.txt", "src2/x.txt"]);
+    leave_location();
+
+    run("overwrite", "");
+    assert_eq!(read("out/x.txt"), "22");
+    check_moves(&vec!["src1/x.txt"]);
+    leave_location();
+
+    run("rename", "");
+    assert_eq!(read("out/x_1.txt"), "1");
+    assert_eq!(read("out/x_2.txt"), "22");
+    assert_eq!(read("out/x.txt"), "");
+    leave_location();
+
+    run("rename-paren", "");
+    assert_eq!(read("out/x (1).txt"), "1");
+    assert_eq!(read("out/x (2).txt"), "22");
+    leave_location();
+
+    run("newer", "");
+    assert_eq!(read("out/x.txt"), "1");
+    check_moves(&vec!["src2/x.txt"]);
+    leave_location();
+
+    run("larger", "");
+    assert_eq!(read("out/x.txt"), "22");
+    check_moves(&vec!["src1/x.txt"]);
+    leave_location();
+
+    run("ask", "r\no\n");
+    assert_eq!(read("out/x_1.txt"), "1");
+    assert_eq!(read("out/x.txt"), "22");
+    leave_location();
+
+    // Names taken by existing files are reported before anything is moved, with or without `--on-conflict error`
+    for args in [&["--on-conflict", "error"][..], &[]] {
+        let command = prepare_location(&vec!["a1", "a2", "a3", "b2"], &vec![]);
+        command
+            .unwrap()
+            .args(args)
+            .args(["a*", "b#1"])
+            .assert()
+            .failure();
+        check_moves(&vec!["a1", "a2", "a3", "b2"]);
+        assert!(!Path::new("b1").exists());
+        leave_location();
+    }
+}
+
+#[test]
+#[serial]
+fn test_move_chains() {
+    let read = |path: &str| fs::read_to_string(path).unwrap();
+    // `2 -> 1` is planned first, but runs after `1 -> 0` has freed the name
+    for strategy in [None, Some("overwrite"), Some("rename")] {
+        let command = prepare_location(&vec![], &vec![]);
+        fs::write("1.txt", "one").unwrap();
+        fs::write("2.txt", "two").unwrap();
+        let mut command = command.unwrap();
+        if let Some(strategy) = strategy {
+            command.args(["--on-conflict", strategy]);
+        }
+        command
+            .args(["--reverse", "*.txt", "#{1 - 1}.txt"])
+            .unwrap();
+        assert_eq!(read("0.txt"), "one");
+        assert_eq!(read("1.txt"), "two");
+        assert!(!Path::new("2.txt").exists());
+        leave_location();
+    }
+
+    // A skipped move keeps its source, so the name is not given to another file
+    let command = prepare_location(&vec![], &vec!["0.txt"]);
+    fs::write("1.txt", "one").unwrap();
+    fs::write("2.txt", "two").unwrap();
+    command
+        .unwrap()
+        .args([
+            "--on-conflict",
+            "skip",
+            "--reverse",
+            "*.txt",
+            "#{1 - 1}.txt",
+        ])
+        .unwrap();
+    assert_eq!(read("1.txt"), "one");
+    assert_eq!(read("2.txt"), "two");
+    leave_location();
+
+    // Swapping names has no order to run in
+    let command = prepare_location(&vec!["a.txt", "b.txt"], &vec![]);
+    command
+        .unwrap()
+        .args(["*.txt", "#{?1~a:b:a}.txt"])
+        .assert()
+        .failure();
+    check_moves(&vec!["a.txt", "b.txt"]);
+    leave_location()
+}